@@ -0,0 +1,232 @@
+use crate::{has_permission, Handler, Origin};
+use serde::Deserialize;
+use serenity::client::Context;
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use serenity::model::Permissions;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How far back a clear goes, in messages.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClearLimits {
+    /// Number of messages looked through by default.
+    pub lookback: u64,
+    /// Number of messages looked through at most.
+    pub max_lookback: u64,
+}
+
+impl Default for ClearLimits {
+    fn default() -> Self {
+        ClearLimits {
+            lookback: 100,
+            max_lookback: 1000,
+        }
+    }
+}
+
+/// Time a clear waits for its confirmation.
+const CONFIRM_DELAY: Duration = Duration::from_secs(60);
+/// Age past which Discord refuses to delete messages in bulk.
+const BULK_MAX_AGE: i64 = 14 * 24 * 60 * 60;
+
+/// A clear waiting to be confirmed.
+pub struct Request {
+    /// Whether every message is deleted rather than only the bot's ones.
+    all: bool,
+    lookback: u64,
+    asked: Instant,
+}
+
+/// Clears waiting for confirmation, by channel and user who asked for them.
+pub type Pending = Mutex<HashMap<(ChannelId, UserId), Request>>;
+
+/// Handles `!dice clear [all] [N]`, asking for confirmation before deleting the bot's
+/// messages, or every one with `all`, among the last `N`, and `!dice clear confirm`.
+/// `before` is the message of the command, which is kept.
+pub async fn clear(
+    handler: &Handler,
+    ctx: &Context,
+    origin: &Origin,
+    before: MessageId,
+    args: &str,
+) -> String {
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    if args == ["confirm"] {
+        let request = handler
+            .pending_clears
+            .lock()
+            .unwrap()
+            .remove(&(origin.channel, origin.user));
+        return match request {
+            Some(r) if r.asked.elapsed() <= CONFIRM_DELAY => {
+                run(ctx, origin.channel, before, &r).await
+            }
+            _ => "There is no clear to confirm".to_owned(),
+        };
+    }
+
+    // commands are written with the first prefix of the guild, as in its help
+    let prefix = &handler.config.prefixes(origin.guild)[0];
+    let (all, lookback) = match parse(&args, handler.config.clear, prefix) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+
+    if all {
+        let guild = match origin.guild {
+            Some(g) => g,
+            None => return "Only my messages can be cleared here".to_owned(),
+        };
+        match has_permission(ctx, guild, origin.user, Permissions::MANAGE_MESSAGES).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return "Clearing every message takes the Manage Messages permission".to_owned()
+            }
+            Err(why) => return format!("Unable to check your permissions : {}", why),
+        }
+    }
+
+    let request = Request {
+        all,
+        lookback,
+        asked: Instant::now(),
+    };
+    handler
+        .pending_clears
+        .lock()
+        .unwrap()
+        .insert((origin.channel, origin.user), request);
+    confirmation(all, lookback, prefix)
+}
+
+/// Reads `[all] [N]`, returning whether every message is cleared and how many are looked
+/// through.
+fn parse(args: &[&str], limits: ClearLimits, prefix: &str) -> Result<(bool, u64), String> {
+    let (all, rest) = match args.split_first() {
+        Some((&"all", rest)) => (true, rest),
+        _ => (false, args),
+    };
+    let lookback = match rest {
+        [] => limits.lookback,
+        [n] => match n.parse::<u64>() {
+            Ok(n) if (1..=limits.max_lookback).contains(&n) => n,
+            _ => {
+                return Err(format!(
+                    "The number of messages to look through should be between 1 and {}",
+                    limits.max_lookback
+                ))
+            }
+        },
+        _ => {
+            return Err(format!(
+                "Usage : {} clear [all] [number of messages]",
+                prefix
+            ))
+        }
+    };
+    Ok((all, lookback))
+}
+
+fn confirmation(all: bool, lookback: u64, prefix: &str) -> String {
+    format!(
+        "This deletes {} among the last {} messages of this channel. Send `{} clear confirm` within {} seconds to go on.",
+        if all { "every message" } else { "my messages" },
+        lookback,
+        prefix,
+        CONFIRM_DELAY.as_secs()
+    )
+}
+
+/// Deletes the messages `request` asks for, returning how many were.
+async fn run(ctx: &Context, channel: ChannelId, before: MessageId, request: &Request) -> String {
+    let me = match ctx.http.get_current_user().await {
+        Ok(me) => me.id,
+        Err(why) => return format!("Unable to clear the channel : {}", why),
+    };
+
+    let mut messages: Vec<Message> = vec![];
+    let mut last = before;
+    while (messages.len() as u64) < request.lookback {
+        let limit = (request.lookback - messages.len() as u64).min(100);
+        let page = match channel
+            .messages(&ctx.http, |m| m.before(last).limit(limit))
+            .await
+        {
+            Ok(page) => page,
+            Err(why) => return format!("Unable to clear the channel : {}", why),
+        };
+        match page.last() {
+            Some(oldest) => last = oldest.id,
+            None => break,
+        }
+        messages.extend(page);
+    }
+    messages.retain(|m| request.all || m.author.id == me);
+
+    // messages can only be deleted in bulk by a bot allowed to manage them, and while they
+    // are recent enough
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let (recent, old): (Vec<_>, Vec<_>) = messages
+        .iter()
+        .map(|m| (m.id, m.timestamp.unix_timestamp()))
+        .partition(|(_, time)| request.all && now - time < BULK_MAX_AGE - 60);
+
+    let mut deleted = 0;
+    for chunk in recent.chunks(100) {
+        let ids = chunk.iter().map(|(id, _)| *id);
+        let res = if chunk.len() == 1 {
+            channel.delete_message(&ctx.http, chunk[0].0).await
+        } else {
+            channel.delete_messages(&ctx.http, ids).await
+        };
+        match res {
+            Ok(()) => deleted += chunk.len(),
+            Err(why) => return format!("Deleted {} messages, then failed : {}", deleted, why),
+        }
+    }
+    for (id, _) in old {
+        match channel.delete_message(&ctx.http, id).await {
+            Ok(()) => deleted += 1,
+            Err(why) => return format!("Deleted {} messages, then failed : {}", deleted, why),
+        }
+    }
+    format!("Deleted {} messages", deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let limits = ClearLimits::default();
+        let parse = |args: &str| parse(&args.split_whitespace().collect::<Vec<_>>(), limits, "!r");
+        assert_eq!(parse(""), Ok((false, 100)));
+        assert_eq!(parse("all"), Ok((true, 100)));
+        assert_eq!(parse("all 20"), Ok((true, 20)));
+        assert_eq!(parse("20"), Ok((false, 20)));
+        let range = "The number of messages to look through should be between 1 and 1000";
+        assert_eq!(parse("0").unwrap_err(), range);
+        assert_eq!(parse("1001").unwrap_err(), range);
+        assert_eq!(parse("all x").unwrap_err(), range);
+        assert_eq!(
+            parse("all 20 30").unwrap_err(),
+            "Usage : !r clear [all] [number of messages]"
+        );
+    }
+
+    #[test]
+    fn confirmations() {
+        assert_eq!(
+            confirmation(false, 100, "!r"),
+            "This deletes my messages among the last 100 messages of this channel. Send `!r clear confirm` within 60 seconds to go on."
+        );
+        assert!(confirmation(true, 20, "!dice")
+            .starts_with("This deletes every message among the last 20 messages"));
+    }
+}
@@ -0,0 +1,224 @@
+use dicer_rs::evaluator::Limits;
+use dicer_rs::{probabilities, statistics};
+use dicer_rs::{Error, Interpreter};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage : dicer-rs [COMMAND]
+
+Commands :
+  run FILE [--seed N]       runs the program in FILE, or read from the standard input if FILE is -
+  eval PROGRAM [--seed N]   runs PROGRAM, also written -e PROGRAM
+  prob EXPR                 shows the probability of every result of an expression, e.g. 4d6h3s
+  stats PROGRAM [--runs N]  runs PROGRAM many times, 10000 by default, and shows statistics on its output
+  repl                      runs programs typed line by line, :help listing its commands
+  bot [--config PATH]       runs the Discord bot
+  help                      shows this help
+
+Without a command, programs are typed line by line if the standard input is a terminal, and read from it as a whole
+otherwise.
+
+Exit codes : 0 on success, 1 when a file cannot be read or the bot fails, 2 on invalid arguments, and 3, 4 or 5 when a
+program fails to lex, to parse or to run.";
+
+pub const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Lex(..) => 3,
+        Error::Parse(..) => 4,
+        Error::Runtime(..) => 5,
+    }
+}
+
+enum Command {
+    Run { file: String, seed: Option<u64> },
+    Eval { program: String, seed: Option<u64> },
+    Prob(String),
+    Stats { program: String, runs: usize },
+    Repl,
+    Bot { config: Option<PathBuf> },
+    Help,
+}
+
+/// Runs the command given by `args`, the arguments of the process, returning its exit code.
+pub fn main(args: Vec<String>) -> i32 {
+    let command = match parse(args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    // the bot logs more, once its config is read
+    if !matches!(command, Command::Bot { .. }) {
+        crate::init_logging("warn");
+    }
+    match command {
+        Command::Run { file, seed } => {
+            let program = if file == "-" {
+                let mut program = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut program)
+                    .map(|_| program)
+            } else {
+                std::fs::read_to_string(&file)
+            };
+            match program {
+                Ok(program) => eval(&program, seed),
+                Err(e) => {
+                    eprintln!("unable to read {} : {}", file, e);
+                    EXIT_FAILURE
+                }
+            }
+        }
+        Command::Eval { program, seed } => eval(&program, seed),
+        Command::Prob(expr) => report(&expr, probabilities(&expr, Limits::default())),
+        Command::Stats { program, runs } => {
+            report(&program, statistics(&program, runs, Limits::default()))
+        }
+        Command::Repl => crate::repl::run(),
+        Command::Bot { config } => crate::bot(config.as_deref()),
+        Command::Help => {
+            println!("{}", USAGE);
+            0
+        }
+    }
+}
+
+fn parse(args: Vec<String>) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let name = match args.next() {
+        Some(name) => name,
+        None if std::io::stdin().is_terminal() => return Ok(Command::Repl),
+        None => {
+            return Ok(Command::Run {
+                file: "-".to_owned(),
+                seed: None,
+            })
+        }
+    };
+
+    // `--name value` options, the other arguments being joined with spaces
+    let mut options = HashMap::new();
+    let mut words = vec![];
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(option) => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value of --{}", option))?;
+                options.insert(option.to_owned(), value);
+            }
+            None => words.push(arg),
+        }
+    }
+    let text = words.join(" ");
+
+    let command = match name.as_str() {
+        "run" if words.len() == 1 => Command::Run {
+            file: text,
+            seed: option(&mut options, "seed")?,
+        },
+        "run" => return Err("Expected a single file to run".to_owned()),
+        "eval" | "-e" if !words.is_empty() => Command::Eval {
+            program: text,
+            seed: option(&mut options, "seed")?,
+        },
+        "prob" if !words.is_empty() => Command::Prob(text),
+        "stats" if !words.is_empty() => Command::Stats {
+            program: text,
+            runs: option(&mut options, "runs")?.unwrap_or(10_000),
+        },
+        "eval" | "-e" | "prob" | "stats" => return Err(format!("Expected a program to {}", name)),
+        "repl" | "bot" | "help" | "--help" | "-h" if !words.is_empty() => {
+            return Err(format!("Unexpected argument '{}'", words[0]))
+        }
+        "repl" => Command::Repl,
+        "bot" => Command::Bot {
+            config: options.remove("config").map(Into::into),
+        },
+        "help" | "--help" | "-h" => Command::Help,
+        _ => return Err(format!("Unknown command '{}'", name)),
+    };
+    match options.keys().next() {
+        Some(option) => Err(format!("Unknown option --{} for {}", option, name)),
+        None => Ok(command),
+    }
+}
+
+/// Takes the value of the option `name` out of `options`.
+fn option<T: std::str::FromStr>(
+    options: &mut HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, String> {
+    match options.remove(name) {
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid value of --{} : '{}'", name, v)),
+        None => Ok(None),
+    }
+}
+
+fn eval(program: &str, seed: Option<u64>) -> i32 {
+    let mut interpreter = match seed {
+        Some(seed) => Interpreter::seeded(Limits::default(), seed),
+        None => Interpreter::default(),
+    };
+    let out = interpreter.eval(program).map(|o| o.text);
+    report(program, out)
+}
+
+/// Prints the output of `program`, or its error, returning the exit code it leads to.
+pub fn report(program: &str, out: Result<String, Error>) -> i32 {
+    match out {
+        Ok(out) => {
+            print!("{}", out);
+            if !out.is_empty() && !out.ends_with('\n') {
+                println!();
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e.render(program));
+            exit_code(&e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &str) -> i32 {
+        report(
+            program,
+            Interpreter::seeded(Limits::default(), 1)
+                .eval(program)
+                .map(|o| o.text),
+        )
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(run("$1d6"), 0);
+        assert_eq!(run("$1d6 #"), 3);
+        assert_eq!(run("$1)"), 4);
+        assert_eq!(run("$1d0"), 5);
+        assert_eq!(report("prob", probabilities("1d0", Limits::default())), 5);
+        assert_eq!(main(vec!["run".to_owned()]), EXIT_USAGE);
+        assert_eq!(main(vec!["nope".to_owned()]), EXIT_USAGE);
+        assert_eq!(
+            main(vec![
+                "stats".to_owned(),
+                "--runs".to_owned(),
+                "x".to_owned(),
+                "$1".to_owned()
+            ]),
+            EXIT_USAGE
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::clear::ClearLimits;
+use crate::reply::OutputLimits;
+use crate::storage::VariableLimits;
+use dicer_rs::evaluator::Limits;
+use serde::Deserialize;
+use serenity::model::id::GuildId;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Config file read when none is given.
+const DEFAULT_PATH: &str = "dicer.toml";
+
+/// Settings of the bot, read from a TOML file. Every key is optional.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Token of the bot, the DICER_TOKEN environment variable taking precedence.
+    token: Option<String>,
+    /// SQLite database the bot keeps its state in.
+    pub database: PathBuf,
+    /// Directory to keep variables and macros in as plain files, instead of the database.
+    pub variables_dir: Option<PathBuf>,
+    /// Text starting the commands sent as messages, such as `!dice`.
+    prefixes: Vec<String>,
+    /// Replaces the help, which lists the commands.
+    help: Option<String>,
+    /// What is logged, DICER_LOG taking precedence, e.g. `info,dicer_rs::evaluator=trace`.
+    pub log: Option<String>,
+    features: Features,
+    limits: ProgramLimits,
+    pub output: OutputLimits,
+    pub clear: ClearLimits,
+    pub variables: VariableLimits,
+    /// Overrides by guild id.
+    guilds: HashMap<String, GuildConfig>,
+}
+
+/// Parts of the bot that can be turned off.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Whether commands sent as messages are answered, on top of slash commands.
+    pub prefix_commands: bool,
+    pub slash_commands: bool,
+    /// Whether the dice behind the output of a program are shown in an embed.
+    pub embeds: bool,
+    pub secret_rolls: bool,
+    pub clear: bool,
+    /// Whether messages ending with "is this true?" are answered with a d6.
+    pub easter_egg: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            prefix_commands: true,
+            slash_commands: true,
+            embeds: true,
+            secret_rolls: true,
+            clear: true,
+            easter_egg: true,
+        }
+    }
+}
+
+/// Highest nesting the threads rolling programs have the stack for.
+const MAX_NESTING: usize = 1000;
+
+/// Bounds on the work of a single program, see [`Limits`].
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProgramLimits {
+    max_steps: u64,
+    max_len: usize,
+    max_depth: usize,
+    max_nesting: usize,
+    max_output: usize,
+}
+
+impl Default for ProgramLimits {
+    fn default() -> Self {
+        let limits = Limits::default();
+        ProgramLimits {
+            max_steps: limits.max_steps,
+            max_len: limits.max_len,
+            max_depth: limits.max_depth,
+            max_nesting: limits.max_nesting,
+            max_output: limits.max_output,
+        }
+    }
+}
+
+/// Settings of a guild overriding the global ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GuildConfig {
+    prefixes: Option<Vec<String>>,
+    help: Option<String>,
+    prefix_commands: Option<bool>,
+    embeds: Option<bool>,
+    secret_rolls: Option<bool>,
+    clear: Option<bool>,
+    easter_egg: Option<bool>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            token: None,
+            database: PathBuf::from("dicer.db"),
+            variables_dir: None,
+            prefixes: vec!["!dice".to_owned()],
+            help: None,
+            log: None,
+            features: Features::default(),
+            limits: ProgramLimits::default(),
+            output: OutputLimits::default(),
+            clear: ClearLimits::default(),
+            variables: VariableLimits::default(),
+            guilds: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads and checks the config at `path`, or at `dicer.toml` if there is one when no
+    /// path is given, the defaults being used otherwise.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(p) => p,
+            None if Path::new(DEFAULT_PATH).exists() => Path::new(DEFAULT_PATH),
+            None => return Ok(Config::default()),
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {} : {}", path.display(), e))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| format!("invalid config {} : {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("invalid config {} : {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_prefixes(&self.prefixes)?;
+        if let Some(log) = &self.log {
+            tracing_subscriber::EnvFilter::try_new(log)
+                .map_err(|e| format!("invalid log filter '{}' : {}", log, e))?;
+        }
+        if self.limits.max_steps == 0
+            || self.limits.max_len == 0
+            || self.limits.max_depth == 0
+            || self.limits.max_nesting == 0
+            || self.limits.max_output == 0
+        {
+            return Err("limits cannot be 0".to_owned());
+        }
+        // deeper programs would overflow the stack of the threads rolling them
+        if self.limits.max_depth > MAX_NESTING || self.limits.max_nesting > MAX_NESTING {
+            return Err(format!(
+                "limits.max_depth and limits.max_nesting cannot be more than {}",
+                MAX_NESTING
+            ));
+        }
+        if self.output.max_messages == 0 || self.output.max_len == 0 {
+            return Err("output limits cannot be 0".to_owned());
+        }
+        if !(1..=self.clear.max_lookback).contains(&self.clear.lookback) {
+            return Err("clear.lookback should be between 1 and clear.max_lookback".to_owned());
+        }
+        if self.variables.max_count == 0 || self.variables.max_len == 0 {
+            return Err("variables limits cannot be 0".to_owned());
+        }
+        for (id, guild) in &self.guilds {
+            id.parse::<u64>()
+                .map_err(|_| format!("'{}' is not a guild id", id))?;
+            if let Some(prefixes) = &guild.prefixes {
+                check_prefixes(prefixes).map_err(|e| format!("guild {} : {}", id, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Token of the bot, from DICER_TOKEN or else the config.
+    pub fn token(&self) -> Result<String, String> {
+        match std::env::var("DICER_TOKEN") {
+            Ok(token) => Ok(token),
+            Err(_) => self.token.clone().ok_or_else(|| {
+                "no token given : set the DICER_TOKEN environment variable or token in the config"
+                    .to_owned()
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_steps: self.limits.max_steps,
+            max_len: self.limits.max_len,
+            max_depth: self.limits.max_depth,
+            max_nesting: self.limits.max_nesting,
+            max_output: self.limits.max_output,
+        }
+    }
+
+    fn guild(&self, guild: Option<GuildId>) -> Option<&GuildConfig> {
+        self.guilds.get(&guild?.0.to_string())
+    }
+
+    /// Features enabled in `guild`, or in direct messages if there is none.
+    pub fn features(&self, guild: Option<GuildId>) -> Features {
+        let mut out = self.features;
+        if let Some(g) = self.guild(guild) {
+            out.prefix_commands = g.prefix_commands.unwrap_or(out.prefix_commands);
+            out.embeds = g.embeds.unwrap_or(out.embeds);
+            out.secret_rolls = g.secret_rolls.unwrap_or(out.secret_rolls);
+            out.clear = g.clear.unwrap_or(out.clear);
+            out.easter_egg = g.easter_egg.unwrap_or(out.easter_egg);
+        }
+        out
+    }
+
+    pub fn prefixes(&self, guild: Option<GuildId>) -> &[String] {
+        match self.guild(guild).and_then(|g| g.prefixes.as_ref()) {
+            Some(p) => p,
+            None => &self.prefixes,
+        }
+    }
+
+    /// Help shown in `guild`, the commands being written with its first prefix.
+    pub fn help(&self, guild: Option<GuildId>) -> String {
+        match self
+            .guild(guild)
+            .and_then(|g| g.help.as_ref())
+            .or(self.help.as_ref())
+        {
+            Some(help) => help.clone(),
+            None => crate::HELP.replace("!dice", &self.prefixes(guild)[0]),
+        }
+    }
+}
+
+fn check_prefixes(prefixes: &[String]) -> Result<(), String> {
+    if prefixes.is_empty() {
+        return Err("there should be at least one prefix".to_owned());
+    }
+    if let Some(p) = prefixes
+        .iter()
+        .find(|p| p.trim().is_empty() || p.contains(char::is_whitespace))
+    {
+        return Err(format!(
+            "invalid prefix '{}' : prefixes cannot be empty or hold spaces",
+            p
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guild_prefixes() {
+        let config: Config = toml::from_str(
+            r#"
+            prefixes = ["!dice", "!d"]
+            [guilds.1]
+            prefixes = ["!r"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.prefixes(None), ["!dice", "!d"]);
+        assert_eq!(config.prefixes(Some(GuildId(2))), ["!dice", "!d"]);
+        assert_eq!(config.prefixes(Some(GuildId(1))), ["!r"]);
+        assert!(config.help(Some(GuildId(1))).contains("!r clear all 50"));
+        assert!(!config.help(Some(GuildId(1))).contains("!dice"));
+        assert!(config.help(None).contains("!dice clear all 50"));
+    }
+
+    #[test]
+    fn invalid_prefixes() {
+        let invalid = |toml: &str| {
+            toml::from_str::<Config>(toml)
+                .unwrap()
+                .validate()
+                .unwrap_err()
+        };
+        assert_eq!(
+            invalid("prefixes = []"),
+            "there should be at least one prefix"
+        );
+        assert_eq!(
+            invalid("[guilds.1]\nprefixes = [\"! r\"]"),
+            "guild 1 : invalid prefix '! r' : prefixes cannot be empty or hold spaces"
+        );
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let validate = |toml: &str| toml::from_str::<Config>(toml).unwrap().validate();
+        assert_eq!(
+            validate("[limits]\nmax_nesting = 0").unwrap_err(),
+            "limits cannot be 0"
+        );
+        assert_eq!(
+            validate("[limits]\nmax_depth = 1001").unwrap_err(),
+            "limits.max_depth and limits.max_nesting cannot be more than 1000"
+        );
+        assert!(validate("[limits]\nmax_depth = 1000\nmax_nesting = 1000").is_ok());
+    }
+}
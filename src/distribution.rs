@@ -0,0 +1,571 @@
+use crate::evaluator::{
+    binary_op, reroll_matches, rerolls_every_side, successes_op, unary_op, unparenthesise, EvArray,
+    Limits,
+};
+use crate::parser::{Expr, Stmt};
+use std::collections::HashMap;
+
+/// Largest number of distinct outcomes a distribution may reach before giving up.
+const MAX_OUTCOMES: usize = 100_000;
+
+/// Exact probability distribution of an expression, as its distinct outcomes along with
+/// their probabilities.
+///
+/// The dice of a single roll are independent and identically distributed, so their order
+/// carries no information : rolls are represented sorted in ascending order, which keeps
+/// `4d6h3s` to 126 outcomes instead of 1296. Indexing a roll with `@` hence gives the
+/// distribution of the n-th lowest die.
+#[derive(Clone, Debug)]
+pub struct Dist(Vec<(EvArray, f64)>);
+
+/// Work done computing a distribution, every value of every outcome built counting as a
+/// step against the limits of programs.
+struct Budget {
+    limits: Limits,
+    steps: u64,
+    /// Number of expressions being evaluated within one another.
+    nesting: usize,
+}
+
+impl Budget {
+    fn tick(&mut self, n: usize) -> Result<(), String> {
+        self.steps = self.steps.saturating_add(n as u64);
+        self.limits.check_steps(self.steps)
+    }
+}
+
+fn key(v: &EvArray, out: &mut Vec<u64>) {
+    match v {
+        EvArray::F(f) => {
+            out.push(0);
+            out.push(if *f == 0. { 0 } else { f.to_bits() });
+        }
+        EvArray::A(a) => {
+            out.push(1);
+            out.push(a.len() as u64);
+            for i in a {
+                key(i, out);
+            }
+        }
+    }
+}
+
+impl Dist {
+    fn certain(v: EvArray) -> Self {
+        Dist(vec![(v, 1.)])
+    }
+
+    /// Builds a distribution, merging identical outcomes.
+    fn collect(outcomes: impl IntoIterator<Item = (EvArray, f64)>) -> Result<Self, String> {
+        let mut index: HashMap<Vec<u64>, usize> = HashMap::new();
+        let mut out: Vec<(EvArray, f64)> = vec![];
+        for (v, p) in outcomes {
+            if p == 0. {
+                continue;
+            }
+            let mut k = vec![];
+            key(&v, &mut k);
+            match index.get(&k) {
+                Some(i) => out[*i].1 += p,
+                None => {
+                    if out.len() >= MAX_OUTCOMES {
+                        return Err(format!(
+                            "Too many possible results to compute the probabilities exactly (more than {})",
+                            MAX_OUTCOMES
+                        ));
+                    }
+                    index.insert(k, out.len());
+                    out.push((v, p));
+                }
+            }
+        }
+        Ok(Dist(out))
+    }
+
+    fn map(
+        &self,
+        b: &mut Budget,
+        f: impl Fn(EvArray) -> Result<EvArray, String>,
+    ) -> Result<Self, String> {
+        let mut out = vec![];
+        for (v, p) in &self.0 {
+            let v = f(v.clone())?;
+            b.tick(v.deep_len())?;
+            out.push((v, *p));
+        }
+        Dist::collect(out)
+    }
+
+    /// Distribution of `f(a, b)` for `a` and `b` independently drawn from `self` and `other`.
+    fn combine(
+        &self,
+        other: &Dist,
+        b: &mut Budget,
+        f: impl Fn(EvArray, EvArray) -> Result<EvArray, String>,
+    ) -> Result<Self, String> {
+        if self.0.len().saturating_mul(other.0.len()) > MAX_OUTCOMES * 10 {
+            return Err(format!(
+                "Too many possible results to compute the probabilities exactly (more than {})",
+                MAX_OUTCOMES
+            ));
+        }
+        let mut out = vec![];
+        for (a, pa) in &self.0 {
+            for (v, pv) in &other.0 {
+                let v = f(a.clone(), v.clone())?;
+                b.tick(v.deep_len())?;
+                out.push((v, pa * pv));
+            }
+        }
+        Dist::collect(out)
+    }
+
+    /// Distribution of an array of `n` values independently drawn from `self`, sorted in
+    /// ascending order.
+    fn iid(&self, n: usize, b: &mut Budget) -> Result<Self, String> {
+        let mut faces = self.0.clone();
+        faces.sort_by(|a, b| compare(&a.0, &b.0));
+
+        // number of multisets of size n over the faces
+        let mut count = 1.;
+        for i in 0..faces.len().saturating_sub(1) {
+            count = count * (n + i + 1) as f64 / (i + 1) as f64;
+        }
+        if count > MAX_OUTCOMES as f64 {
+            return Err(format!(
+                "Too many possible results to compute the probabilities exactly (more than {})",
+                MAX_OUTCOMES
+            ));
+        }
+        // every outcome holds the n values
+        b.tick((count * n as f64) as usize)?;
+
+        let mut out = vec![];
+        multisets(&faces, n, 1., &mut vec![], &mut out);
+        Dist::collect(out)
+    }
+
+    /// Distribution of the sum of `n` numbers independently drawn from `self`.
+    fn iid_sum(&self, n: usize, b: &mut Budget) -> Result<Self, String> {
+        let mut out = Dist::certain(EvArray::F(0.));
+        for _ in 0..n {
+            out = out.combine(self, b, |x, y| binary_op(x, '+', y))?;
+        }
+        Ok(out)
+    }
+
+    /// Mixes distributions, each being picked with the given probability.
+    fn mix(parts: Vec<(Dist, f64)>) -> Result<Self, String> {
+        Dist::collect(
+            parts
+                .into_iter()
+                .flat_map(|(d, p)| d.0.into_iter().map(move |(v, q)| (v, p * q))),
+        )
+    }
+
+    fn is_numeric(&self) -> bool {
+        self.0.iter().all(|(v, _)| matches!(v, EvArray::F(_)))
+    }
+}
+
+fn compare(a: &EvArray, b: &EvArray) -> std::cmp::Ordering {
+    match (a, b) {
+        (EvArray::F(a), EvArray::F(b)) => a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal),
+        (EvArray::F(_), EvArray::A(_)) => std::cmp::Ordering::Less,
+        (EvArray::A(_), EvArray::F(_)) => std::cmp::Ordering::Greater,
+        (EvArray::A(a), EvArray::A(b)) => {
+            for (a, b) in a.iter().zip(b) {
+                let c = compare(a, b);
+                if c != std::cmp::Ordering::Equal {
+                    return c;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+    }
+}
+
+/// Enumerates every way of drawing `n` values from `faces`, with the probability of getting
+/// that exact multiset (multinomial coefficient included).
+fn multisets(
+    faces: &[(EvArray, f64)],
+    n: usize,
+    p: f64,
+    current: &mut Vec<EvArray>,
+    out: &mut Vec<(EvArray, f64)>,
+) {
+    if n == 0 {
+        out.push((EvArray::A(current.clone()), p));
+        return;
+    }
+    if faces.len() == 1 {
+        let len = current.len();
+        current.extend(std::iter::repeat_n(faces[0].0.clone(), n));
+        out.push((EvArray::A(current.clone()), p * faces[0].1.powi(n as i32)));
+        current.truncate(len);
+        return;
+    }
+    // picks how many of the n values show the first face
+    let mut binomial = 1.;
+    for k in 0..=n {
+        let len = current.len();
+        current.extend(std::iter::repeat_n(faces[0].0.clone(), k));
+        multisets(
+            &faces[1..],
+            n - k,
+            p * binomial * faces[0].1.powi(k as i32),
+            current,
+            out,
+        );
+        current.truncate(len);
+        binomial = binomial * (n - k) as f64 / (k + 1) as f64;
+    }
+}
+
+fn push(a: EvArray, b: EvArray) -> Result<EvArray, String> {
+    match a {
+        EvArray::A(mut a) => {
+            a.push(b);
+            Ok(EvArray::A(a))
+        }
+        f => Ok(f),
+    }
+}
+
+/// Distribution of a single die with the given faces.
+fn die(faces: &EvArray) -> Result<Dist, String> {
+    match faces {
+        EvArray::F(s) if *s >= 1. => {
+            let s = *s as usize;
+            Dist::collect((1..=s).map(|n| (EvArray::F(n as f64), 1. / s as f64)))
+        }
+        EvArray::A(s) if !s.is_empty() => {
+            Dist::collect(s.iter().map(|v| (v.clone(), 1. / s.len() as f64)))
+        }
+        _ => Err("Dice need at least one side".to_string()),
+    }
+}
+
+/// Distribution of a single die once rerolled according to the `r` (or `rr` if `repeat`)
+/// operator.
+fn reroll(die: Dist, faces: &EvArray, reroll: &EvArray, repeat: bool) -> Result<Dist, String> {
+    if repeat && rerolls_every_side(faces, reroll) {
+        return Err(
+            "Every side of the dice would be rerolled in call to operator 'rr'".to_string(),
+        );
+    }
+    let rerolled: f64 = die
+        .0
+        .iter()
+        .filter(|(v, _)| reroll_matches(reroll, v))
+        .map(|(_, p)| p)
+        .sum();
+    Dist::collect(die.0.iter().map(|(v, p)| {
+        let kept = if reroll_matches(reroll, v) { 0. } else { *p };
+        if repeat {
+            (v.clone(), kept / (1. - rerolled))
+        } else {
+            (v.clone(), kept + rerolled * p)
+        }
+    }))
+}
+
+/// A number of dice, the distribution of a single one of them and the probability of
+/// rolling that pool.
+type Pool = (usize, Dist, f64);
+
+/// For an expression rolling dice, every pool it may roll.
+fn pools(e: &Expr, b: &mut Budget) -> Result<Option<Vec<Pool>>, String> {
+    let (count, faces, rr) = match unparenthesise(e.clone()) {
+        Expr::Operation(count, 'd', faces) => (*count, *faces, None),
+        Expr::Operation(first, op @ ('r' | 'R'), second) => match unparenthesise(*first) {
+            Expr::Operation(count, 'd', faces) => (*count, *faces, Some((*second, op == 'R'))),
+            _ => {
+                return Err(format!(
+                    "Operator '{}' can only be applied to a dice roll",
+                    if op == 'R' { "rr" } else { "r" }
+                ))
+            }
+        },
+        _ => return Ok(None),
+    };
+    let rr = match rr {
+        Some((e, repeat)) => Some((eval(&e, b)?, repeat)),
+        None => None,
+    };
+
+    let mut out = vec![];
+    for (n, pn) in eval(&count, b)?.0 {
+        let n = match n {
+            EvArray::F(n) => {
+                b.limits.check_len(n as usize)?;
+                n as usize
+            }
+            EvArray::A(_) => {
+                return Err("Cannot infer the number of dice throws from an array".to_string())
+            }
+        };
+        for (s, ps) in &eval(&faces, b)?.0 {
+            match &rr {
+                None => out.push((n, die(s)?, pn * ps)),
+                Some((r, repeat)) => {
+                    for (r, pr) in &r.0 {
+                        out.push((n, reroll(die(s)?, s, r, *repeat)?, pn * ps * pr));
+                    }
+                }
+            }
+        }
+    }
+    Ok(Some(out))
+}
+
+fn eval(e: &Expr, b: &mut Budget) -> Result<Dist, String> {
+    b.limits.check_nesting(b.nesting + 1)?;
+    b.nesting += 1;
+    let out = eval_nested(e, b);
+    b.nesting -= 1;
+    out
+}
+
+/// Computes the distribution of `e`, its nesting being already counted.
+fn eval_nested(e: &Expr, b: &mut Budget) -> Result<Dist, String> {
+    match e {
+        Expr::Val(v) => Ok(Dist::certain(EvArray::F(*v))),
+        Expr::Array(a) => {
+            let mut out = Dist::certain(EvArray::A(vec![]));
+            for i in a.clone().into_inner() {
+                out = out.combine(&eval(&i, b)?, b, push)?;
+            }
+            Ok(out)
+        }
+        Expr::Parenthesised(e) => eval(e, b),
+        Expr::Var(v) => Err(format!(
+            "Variables cannot be used when computing probabilities : '{}'",
+            v
+        )),
+        Expr::Call(name, args) if name == "SUCCESSES" && (2..=3).contains(&args.len()) => {
+            let mut out = Dist::certain(EvArray::A(vec![]));
+            for i in args {
+                out = out.combine(&eval(i, b)?, b, push)?;
+            }
+            out.map(b, |a| match a {
+                EvArray::A(a) => match &a[1] {
+                    EvArray::F(target) => successes_op(
+                        a[0].clone(),
+                        *target,
+                        a.get(2).is_some_and(|o| *o != EvArray::F(0.)),
+                    ),
+                    EvArray::A(_) => Err(
+                        "second argument must be a number in call to function 'SUCCESSES'"
+                            .to_string(),
+                    ),
+                },
+                f => Ok(f),
+            })
+        }
+        Expr::Call(name, _) => Err(format!(
+            "Function '{}' cannot be used when computing probabilities",
+            name
+        )),
+        Expr::Operation(first, op, second) => match op {
+            'd' | 'r' | 'R' => {
+                let mut parts = vec![];
+                for (n, die, p) in pools(e, b)?.unwrap_or_default() {
+                    parts.push((die.iid(n, b)?, p));
+                }
+                Dist::mix(parts)
+            }
+            's' => match pools(first, b)? {
+                Some(pools) if pools.iter().all(|(_, die, _)| die.is_numeric()) => {
+                    let mut parts = vec![];
+                    for (n, die, p) in pools {
+                        parts.push((die.iid_sum(n, b)?, p));
+                    }
+                    Dist::mix(parts)
+                }
+                _ => eval(first, b)?.map(b, |v| unary_op(v, 's')),
+            },
+            '_' | '!' => eval(first, b)?.map(b, |v| unary_op(v, *op)),
+            'x' => {
+                let rhs = eval(second, b)?;
+                let limits = b.limits;
+                let mut parts = vec![];
+                for (n, p) in eval(first, b)?.0 {
+                    match n {
+                        EvArray::F(n) => {
+                            b.limits.check_len(n as usize)?;
+                            let mut out = Dist::certain(EvArray::A(vec![]));
+                            for _ in 0..(n as usize) {
+                                out = out.combine(&rhs, b, push)?;
+                            }
+                            parts.push((out, p));
+                        }
+                        EvArray::A(a) => parts.push((
+                            rhs.map(b, |n| match n {
+                                EvArray::F(n) => {
+                                    limits.check_len((n as usize).saturating_mul(a.len()))?;
+                                    Ok(EvArray::A(
+                                        (0..(n as usize)).flat_map(|_| a.clone()).collect(),
+                                    ))
+                                }
+                                EvArray::A(_) => {
+                                    Err("Cannot infer duplication number from array".to_string())
+                                }
+                            })?,
+                            p,
+                        )),
+                    }
+                }
+                Dist::mix(parts)
+            }
+            _ => {
+                let rhs = eval(second, b)?;
+                eval(first, b)?.combine(&rhs, b, |x, y| binary_op(x, *op, y))
+            }
+        },
+        Expr::None => Err("Unexpected parse artifact".to_owned()),
+    }
+}
+
+/// Computes the exact distribution of the expression of a program made of a single output
+/// statement, such as `$4d6h3s`, within the same `limits` as running it.
+pub fn distribution(t: &[Stmt], limits: Limits) -> Result<Dist, String> {
+    let mut budget = Budget {
+        limits,
+        steps: 0,
+        nesting: 0,
+    };
+    match t {
+        [Stmt::Out(e)] => eval(e, &mut budget),
+        _ => Err("Probabilities can only be computed for a single expression".to_string()),
+    }
+}
+
+/// Renders a distribution as one line per outcome, with its probability and a bar. Results
+/// with a probability that would round to 0.00% are left out.
+pub fn render(d: &Dist) -> String {
+    const MAX_LINES: usize = 100;
+    const BAR: f64 = 30.;
+    const NEGLIGIBLE: f64 = 0.00005;
+
+    let mut outcomes = d.0.clone();
+    let mut out = String::new();
+    if d.is_numeric() {
+        outcomes.sort_by(|a, b| compare(&a.0, &b.0));
+        let mean: f64 = outcomes
+            .iter()
+            .map(|(v, p)| if let EvArray::F(f) = v { f * p } else { 0. })
+            .sum();
+        let var: f64 = outcomes
+            .iter()
+            .map(|(v, p)| {
+                if let EvArray::F(f) = v {
+                    (f - mean).powi(2) * p
+                } else {
+                    0.
+                }
+            })
+            .sum();
+        out = format!(
+            "mean : {:.2}, standard deviation : {:.2}\n",
+            mean,
+            var.sqrt()
+        );
+    } else {
+        outcomes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    }
+
+    let max = outcomes.iter().map(|(_, p)| *p).fold(0., f64::max);
+    let total = outcomes.len();
+    outcomes.retain(|(_, p)| *p >= NEGLIGIBLE);
+    let omitted = total - outcomes.len().min(MAX_LINES);
+    for (v, p) in outcomes.iter().take(MAX_LINES) {
+        out = format!(
+            "{}{} : {:.2}% {}\n",
+            out,
+            v,
+            p * 100.,
+            "#".repeat((p / max * BAR).round() as usize)
+        );
+    }
+    if omitted > 0 {
+        out = format!("{}... {} more results not shown\n", out, omitted);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lex, parse_program};
+
+    fn dist(program: &str) -> Result<Dist, String> {
+        let tokens = lex(program).map_err(|e| e.message().to_owned())?;
+        let (parsed, _) =
+            parse_program(&tokens, program.len()).map_err(|e| e.message().to_owned())?;
+        distribution(&parsed, Limits::default())
+    }
+
+    fn probability(d: &Dist, value: f64) -> f64 {
+        d.0.iter()
+            .filter(|(v, _)| *v == EvArray::F(value))
+            .map(|(_, p)| p)
+            .sum()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn two_dice() {
+        let d = dist("$2d6s").unwrap();
+        assert_eq!(d.0.len(), 11);
+        assert!(close(d.0.iter().map(|(_, p)| p).sum(), 1.));
+        assert!(close(probability(&d, 2.), 1. / 36.));
+        assert!(close(probability(&d, 7.), 6. / 36.));
+        assert!(close(probability(&d, 12.), 1. / 36.));
+    }
+
+    #[test]
+    fn highest_dice() {
+        let d = dist("$4d6h3s").unwrap();
+        let mean: f64 =
+            d.0.iter()
+                .map(|(v, p)| if let EvArray::F(f) = v { f * p } else { 0. })
+                .sum();
+        assert!(close(mean, 15869. / 1296.));
+        assert!(close(probability(&d, 18.), 21. / 1296.));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let d = dist("$1d4s*2+1").unwrap();
+        for v in [3., 5., 7., 9.] {
+            assert!(close(probability(&d, v), 0.25));
+        }
+    }
+
+    #[test]
+    fn single_expression() {
+        assert!(dist("$1d6s $1d6s").is_err());
+        assert!(dist("A=1d6").is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let e = dist("$50000d2s").unwrap_err();
+        assert!(e.starts_with("Execution limit exceeded"), "{}", e);
+        let tokens = lex("$((((1d6))))").unwrap();
+        let (parsed, _) = parse_program(&tokens, 12).unwrap();
+        let limits = |max_nesting| Limits {
+            max_nesting,
+            ..Limits::default()
+        };
+        assert!(distribution(&parsed, limits(6)).is_ok());
+        assert_eq!(
+            distribution(&parsed, limits(5)).unwrap_err(),
+            "Execution limit exceeded : expressions and blocks nested more than 5 deep"
+        );
+    }
+}
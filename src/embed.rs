@@ -0,0 +1,139 @@
+use dicer_rs::evaluator::{DiceRoll, EvArray};
+use serenity::builder::CreateEmbed;
+
+// limits Discord puts on embeds, in characters
+const TITLE_LEN: usize = 256;
+const DESCRIPTION_LEN: usize = 4096;
+const FIELD_NAME_LEN: usize = 256;
+const FIELD_LEN: usize = 1024;
+const MAX_FIELDS: usize = 25;
+const EMBED_LEN: usize = 6000;
+
+/// Whether an output is short enough to be shown in an embed.
+pub fn fits(output: &str) -> bool {
+    output.chars().count() <= DESCRIPTION_LEN
+}
+
+/// Shows the output of `program` along with the dice it rolled, one field per roll. Dropped
+/// dice are struck through, natural maximums are in bold and natural minimums underlined.
+pub fn roll<'a>(
+    e: &'a mut CreateEmbed,
+    program: &str,
+    output: &str,
+    rolls: &[DiceRoll],
+) -> &'a mut CreateEmbed {
+    let title = cut(program, TITLE_LEN);
+    // room left for the fields, some being kept for the footer
+    let mut room = EMBED_LEN - title.chars().count() - output.chars().count() - 40;
+    e.title(title).description(output);
+
+    let mut shown = 0;
+    for roll in rolls.iter().take(MAX_FIELDS) {
+        let name = cut(&roll.label, FIELD_NAME_LEN);
+        let value = dice(roll);
+        let len = name.chars().count() + value.chars().count();
+        if len > room {
+            break;
+        }
+        room -= len;
+        e.field(name, value, true);
+        shown += 1;
+    }
+    if shown < rolls.len() {
+        e.footer(|f| f.text(format!("and {} more rolls", rolls.len() - shown)));
+    }
+    e
+}
+
+/// The dice of a roll followed by their total, as many dice as fit in a field.
+fn dice(roll: &DiceRoll) -> String {
+    let total = match roll.total() {
+        Some(t) => format!(" = {}", t),
+        None => String::new(),
+    };
+    let mut out = String::new();
+    for (i, (v, kept)) in roll.values.iter().zip(&roll.kept).enumerate() {
+        let die = match (v, roll.sides) {
+            _ if !kept => format!("~~{}~~", v),
+            (EvArray::F(f), Some((lo, hi))) if lo < hi && *f == hi => format!("**{}**", v),
+            (EvArray::F(f), Some((lo, hi))) if lo < hi && *f == lo => format!("__{}__", v),
+            _ => v.to_string(),
+        };
+        let sep = if i == 0 { "" } else { " " };
+        if out.len() + sep.len() + die.len() + total.len() + 4 > FIELD_LEN {
+            out.push_str(" …");
+            break;
+        }
+        out.push_str(sep);
+        out.push_str(&die);
+    }
+    if out.is_empty() {
+        out.push_str("no dice");
+    }
+    out + &total
+}
+
+/// The first `len` characters of `s`, an ellipsis marking the cut.
+fn cut(s: &str, len: usize) -> String {
+    if s.chars().count() <= len {
+        return s.to_owned();
+    }
+    s.chars()
+        .take(len - 1)
+        .chain(std::iter::once('…'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d6(values: &[f64], kept: &[bool]) -> DiceRoll {
+        DiceRoll {
+            label: "4d6h3".to_owned(),
+            values: values.iter().map(|v| EvArray::F(*v)).collect(),
+            kept: kept.to_vec(),
+            sides: Some((1., 6.)),
+        }
+    }
+
+    #[test]
+    fn fitting() {
+        assert!(fits(&"a".repeat(DESCRIPTION_LEN)));
+        assert!(!fits(&"a".repeat(DESCRIPTION_LEN + 1)));
+        // characters are counted rather than bytes
+        assert!(fits(&"é".repeat(DESCRIPTION_LEN)));
+    }
+
+    #[test]
+    fn dice_formatting() {
+        let roll = d6(&[6., 1., 3., 1.], &[true, true, true, false]);
+        assert_eq!(dice(&roll), "**6** __1__ 3 ~~1~~ = 10");
+        assert_eq!(dice(&d6(&[], &[])), "no dice = 0");
+
+        let long = d6(&[2.; 1000], &[true; 1000]);
+        let shown = dice(&long);
+        assert!(shown.chars().count() <= FIELD_LEN);
+        assert!(shown.ends_with(" … = 2000"), "{}", shown);
+    }
+
+    #[test]
+    fn embed() {
+        let rolls = vec![d6(&[6., 1., 3., 1.], &[true, true, true, false]); MAX_FIELDS + 2];
+        let mut e = CreateEmbed::default();
+        roll(&mut e, "$4d6h3s", "10\n", &rolls);
+        assert_eq!(e.0["title"], "$4d6h3s");
+        assert_eq!(e.0["description"], "10\n");
+        let fields = e.0["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), MAX_FIELDS);
+        assert_eq!(fields[0]["name"], "4d6h3");
+        assert_eq!(fields[0]["value"], "**6** __1__ 3 ~~1~~ = 10");
+        assert_eq!(e.0["footer"]["text"], "and 2 more rolls");
+    }
+
+    #[test]
+    fn cutting() {
+        assert_eq!(cut("$1d6", 4), "$1d6");
+        assert_eq!(cut("$1d6s", 4), "$1d…");
+    }
+}
@@ -0,0 +1,113 @@
+/// Byte range of a program's source.
+pub type Span = std::ops::Range<usize>;
+
+/// Why a program could not be run, depending on the stage that rejected it, along with the
+/// part of the source responsible for it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Lex(String, Span),
+    Parse(String, Span),
+    /// Runtime errors point at the whole top-level statement they happened in.
+    Runtime(String, Span),
+}
+
+impl Error {
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Lex(m, _) | Error::Parse(m, _) | Error::Runtime(m, _) => m,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Lex(_, s) | Error::Parse(_, s) | Error::Runtime(_, s) => s.clone(),
+        }
+    }
+
+    /// Renders the error followed by the line of `src` it happened on, with its span
+    /// underlined by carets:
+    ///
+    /// ```text
+    /// Invalid Token ')'
+    /// 1 | $(1+2))
+    ///   |       ^
+    /// ```
+    ///
+    /// A span covering several lines is only underlined on its first one.
+    pub fn render(&self, src: &str) -> String {
+        let span = self.span();
+        let start = floor_char_boundary(src, span.start);
+        let end = floor_char_boundary(src, span.end.max(start));
+
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let line = src[line_start..line_end].trim_end_matches('\r');
+        let number = (src[..start].matches('\n').count() + 1).to_string();
+
+        let column = src[line_start..start].chars().count();
+        let width = src[start..end.min(line_end)].chars().count().max(1);
+
+        format!(
+            "{}\n{} | {}\n{} | {}{}",
+            self.message(),
+            number,
+            line,
+            " ".repeat(number.len()),
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    i = i.min(s.len());
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let e = Error::Parse("Invalid Token ')'".to_owned(), 6..7);
+        assert_eq!(
+            e.render("$(1+2))"),
+            "Invalid Token ')'\n1 | $(1+2))\n  |       ^"
+        );
+    }
+
+    #[test]
+    fn render_later_lines() {
+        let src = "A=1\nB=2\n$A+C\n";
+        let e = Error::Runtime("Unknown variable 'C'".to_owned(), 8..12);
+        assert_eq!(e.render(src), "Unknown variable 'C'\n3 | $A+C\n  | ^^^^");
+    }
+
+    #[test]
+    fn render_end_of_program() {
+        // errors at the end of the source still get a caret
+        let e = Error::Parse("Unexpected end".to_owned(), 3..3);
+        assert_eq!(e.render("$1+"), "Unexpected end\n1 | $1+\n  |    ^");
+    }
+
+    #[test]
+    fn render_multibyte() {
+        let e = Error::Lex("Unknown character '€'".to_owned(), 3..6);
+        assert_eq!(
+            e.render("$1 € 2"),
+            "Unknown character '€'\n1 | $1 € 2\n  |    ^"
+        );
+    }
+}
@@ -1,7 +1,9 @@
 use crate::parser::{Expr, Stmt};
 use std::collections::HashMap;
+use tracing::trace;
 
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum EvArray {
@@ -9,19 +11,361 @@ pub enum EvArray {
     A(Vec<EvArray>),
 }
 
+#[derive(Clone, Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// A chain of variable scopes, the innermost one being the last.
+///
+/// Reading a variable walks the chain outwards, assigning to a variable updates
+/// the closest scope that already knows it and only creates it in the innermost
+/// scope otherwise.
+struct Env {
+    frames: Vec<HashMap<String, EvArray>>,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env {
+            frames: vec![HashMap::new()],
+        }
+    }
+}
+
+impl Env {
+    fn get(&self, name: &str) -> Option<&EvArray> {
+        self.frames.iter().rev().find_map(|f| f.get(name))
+    }
+    fn set(&mut self, name: String, val: EvArray) {
+        match self.frames.iter_mut().rev().find(|f| f.contains_key(&name)) {
+            Some(f) => f.insert(name, val),
+            None => self.define(name, val),
+        };
+    }
+    fn define(&mut self, name: String, val: EvArray) -> Option<EvArray> {
+        self.frames.last_mut().unwrap().insert(name, val)
+    }
+    fn push(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+    fn pop(&mut self) {
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+}
+
+/// Bounds on the work a single call to `evaluate` is allowed to do.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Number of statements and expressions evaluated, values copied included.
+    pub max_steps: u64,
+    /// Number of values (nested ones included) a single array may hold.
+    pub max_len: usize,
+    /// Number of nested function calls.
+    pub max_depth: usize,
+    /// Number of expressions and blocks being evaluated within one another, those of the
+    /// functions called included, which bounds the stack taken.
+    pub max_nesting: usize,
+    /// Number of bytes the output may hold.
+    pub max_output: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: 1_000_000,
+            max_len: 100_000,
+            max_depth: 64,
+            max_nesting: 512,
+            max_output: 1_000_000,
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn check_steps(&self, steps: u64) -> Result<(), String> {
+        if steps > self.max_steps {
+            return Err(format!(
+                "Execution limit exceeded : more than {} steps",
+                self.max_steps
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_nesting(&self, n: usize) -> Result<(), String> {
+        if n > self.max_nesting {
+            return Err(format!(
+                "Execution limit exceeded : expressions and blocks nested more than {} deep",
+                self.max_nesting
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_len(&self, n: usize) -> Result<(), String> {
+        if n > self.max_len {
+            return Err(format!(
+                "Execution limit exceeded : arrays cannot hold more than {} values",
+                self.max_len
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Number of dice kept by a state to show how results came about, past which rolls are
+/// no longer recorded.
+const MAX_RECORDED_DICE: usize = 1000;
+
+/// A group of dice rolled by a program, such as the four dice of `4d6`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiceRoll {
+    /// The roll as written, e.g. `4d6h3`.
+    pub label: String,
+    pub values: Vec<EvArray>,
+    /// Whether each die counts, dice dropped by `h` or `l` not doing so.
+    pub kept: Vec<bool>,
+    /// Lowest and highest sides of the dice, when they are numbers.
+    pub sides: Option<(f64, f64)>,
+}
+
+impl DiceRoll {
+    /// Sum of the dice kept, unless some of them are not numbers.
+    pub fn total(&self) -> Option<f64> {
+        let mut total = 0.;
+        for (v, kept) in self.values.iter().zip(&self.kept) {
+            match v {
+                EvArray::F(f) if *kept => total += f,
+                EvArray::F(_) => {}
+                EvArray::A(_) => return None,
+            }
+        }
+        Some(total)
+    }
+}
+
+/// Lowest and highest sides of dice with `faces`, when they are all numbers.
+fn sides(faces: &EvArray) -> Option<(f64, f64)> {
+    match faces {
+        EvArray::F(s) => Some((1., s.floor())),
+        EvArray::A(a) => {
+            let mut out: Option<(f64, f64)> = None;
+            for i in a {
+                let f = match i {
+                    EvArray::F(f) => *f,
+                    EvArray::A(_) => return None,
+                };
+                out = Some(out.map_or((f, f), |(lo, hi)| (lo.min(f), hi.max(f))));
+            }
+            out
+        }
+    }
+}
+
+/// Everything a program can observe or modify while it runs: its variables, the
+/// functions it defined so far, the output it produced and the generator its dice are
+/// rolled with.
+pub struct State {
+    vars: Env,
+    funcs: HashMap<String, Function>,
+    out: String,
+    last: Option<EvArray>,
+    limits: Limits,
+    steps: u64,
+    depth: usize,
+    nesting: usize,
+    stmt: usize,
+    seed: u64,
+    // unlike StdRng, ChaCha8Rng is guaranteed to roll the same numbers from a seed across
+    // versions and platforms, which logged seeds rely on
+    rng: ChaCha8Rng,
+    rolls: Vec<DiceRoll>,
+}
+
+impl State {
+    /// Creates a state rolling dice from a random seed.
+    pub fn new(limits: Limits) -> Self {
+        State::seeded(limits, thread_rng().gen())
+    }
+
+    /// Creates a state rolling dice from `seed` : the same program run from the same seed
+    /// always rolls the same dice.
+    pub fn seeded(limits: Limits, seed: u64) -> Self {
+        State {
+            vars: Env::default(),
+            funcs: HashMap::new(),
+            out: String::new(),
+            last: None,
+            limits,
+            steps: 0,
+            depth: 0,
+            nesting: 0,
+            stmt: 0,
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            rolls: vec![],
+        }
+    }
+
+    /// Seed the dice of this state were first rolled from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Rolls the following dice from `seed`, keeping variables and functions.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Number of steps the last call to `evaluate` took.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Variables of the outermost scope, the only ones left once a program ends.
+    pub fn globals(&self) -> &HashMap<String, EvArray> {
+        &self.vars.frames[0]
+    }
+
+    pub fn set_global(&mut self, name: String, value: EvArray) {
+        self.vars.frames[0].insert(name, value);
+    }
+
+    /// Index of the top-level statement the last call to `evaluate` stopped at.
+    pub fn statement(&self) -> usize {
+        self.stmt
+    }
+
+    /// Takes the value output by the last `$` statement run by the last call to `evaluate`.
+    pub fn take_last(&mut self) -> Option<EvArray> {
+        self.last.take()
+    }
+
+    /// Takes the dice rolled since the last call, in the order they were rolled. Only the
+    /// first thousand dice or so are kept.
+    pub fn take_rolls(&mut self) -> Vec<DiceRoll> {
+        std::mem::take(&mut self.rolls)
+    }
+
+    /// Keeps the dice of a roll, as long as not too many were already kept.
+    fn record(
+        &mut self,
+        label: impl FnOnce() -> String,
+        rolled: &EvArray,
+        sides: Option<(f64, f64)>,
+    ) {
+        let values = match rolled {
+            EvArray::A(a) => a,
+            EvArray::F(_) => return,
+        };
+        let recorded: usize = self.rolls.iter().map(|r| r.values.len()).sum();
+        if recorded + values.len() > MAX_RECORDED_DICE {
+            return;
+        }
+        self.rolls.push(DiceRoll {
+            label: label(),
+            values: values.clone(),
+            kept: vec![true; values.len()],
+            sides,
+        });
+    }
+
+    /// Marks the dice dropped by `op` (`h` or `l`) keeping `n` of `dice`, if they are those
+    /// of the last roll recorded, which happened after `recorded` rolls were.
+    fn record_keep(&mut self, recorded: usize, dice: &EvArray, op: char, n: &EvArray) {
+        if self.rolls.len() != recorded + 1 {
+            return;
+        }
+        let (roll, dice, n) = match (self.rolls.last_mut(), dice, n) {
+            (Some(roll), EvArray::A(dice), EvArray::F(n)) => (roll, dice, *n as usize),
+            _ => return,
+        };
+        if roll.values != *dice || roll.kept.contains(&false) || n > dice.len() {
+            return;
+        }
+        let mut order = (0..dice.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| match (&dice[*a], &dice[*b]) {
+            (EvArray::F(a), EvArray::F(b)) => a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal),
+            _ => std::cmp::Ordering::Equal,
+        });
+        if op == 'h' {
+            order.reverse();
+        }
+        for i in &order[n..] {
+            roll.kept[*i] = false;
+        }
+        roll.label = format!("{}{}{}", roll.label, op, n);
+    }
+
+    /// Runs `f` one level of nesting deeper.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut State) -> Result<T, String>) -> Result<T, String> {
+        self.limits.check_nesting(self.nesting + 1)?;
+        self.nesting += 1;
+        let out = f(self);
+        self.nesting -= 1;
+        out
+    }
+
+    fn tick(&mut self, n: usize) -> Result<(), String> {
+        self.steps = self.steps.saturating_add(n as u64);
+        self.limits.check_steps(self.steps)
+    }
+
+    fn check_len(&self, n: usize) -> Result<(), String> {
+        self.limits.check_len(n)
+    }
+
+    /// Appends a line to the output.
+    fn print(&mut self, line: impl std::fmt::Display) -> Result<(), String> {
+        use std::fmt::Write;
+        let _ = writeln!(self.out, "{}", line);
+        if self.out.len() > self.limits.max_output {
+            return Err(format!(
+                "Execution limit exceeded : the output cannot hold more than {} bytes",
+                self.limits.max_output
+            ));
+        }
+        Ok(())
+    }
+
+    /// Accounts for an array of `n` new values about to be built.
+    fn alloc(&mut self, n: usize) -> Result<(), String> {
+        self.check_len(n)?;
+        self.tick(n)
+    }
+}
+
+#[derive(Debug)]
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(EvArray),
+}
+
+/// Functions provided by the language, which programs cannot redefine.
+pub(crate) const BUILTINS: [&str; 4] = ["CONTAINS", "PUSH", "EXPLODE", "SUCCESSES"];
+
 impl EvArray {
-    fn is_true(&self) -> bool {
-        if matches!(self, Self::F(f) if *f == 0.) {
-            false
-        } else {
-            true
+    pub(crate) fn deep_len(&self) -> usize {
+        match self {
+            EvArray::F(_) => 1,
+            EvArray::A(a) => a.iter().map(|i| i.deep_len()).sum(),
         }
     }
+    fn is_true(&self) -> bool {
+        !matches!(self, Self::F(f) if *f == 0.)
+    }
     fn stringify(&self) -> Result<String, String> {
         match self {
             EvArray::F(f) => {
                 let c = char::from_u32(*f as u32);
-                if let None = c {
+                if c.is_none() {
                     return Err(format!("invalid unicode value : '{}'", *f as u32));
                 }
                 Ok(c.unwrap().to_string())
@@ -31,14 +375,14 @@ impl EvArray {
                 for i in a {
                     if let EvArray::F(f) = i {
                         let c = char::from_u32(*f as u32);
-                        if let None = c {
+                        if c.is_none() {
                             return Err(format!("invalid unicode value : '{}'", *f as u32));
                         }
                         out.push(c.unwrap())
                     } else {
-                        return Err(format!(
-                            "error : operator '~' doesn't automatically flatten"
-                        ));
+                        return Err(
+                            "error : operator '~' doesn't automatically flatten".to_string()
+                        );
                     }
                 }
                 Ok(out)
@@ -52,131 +396,128 @@ impl std::fmt::Display for EvArray {
         if let Self::F(n) = self {
             write!(f, "{}", n)
         } else if let Self::A(a) = self {
-            let mut out = "[".to_owned();
-            for i in a {
-                out = format!("{}{},", out, i);
-            }
-            if out.len() > 1 {
-                out.pop();
+            write!(f, "[")?;
+            for (n, i) in a.iter().enumerate() {
+                if n > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", i)?;
             }
-            out.push(']');
-            write!(f, "{}", out)
+            write!(f, "]")
         } else {
             unreachable!()
         }
     }
 }
 
-fn evaluate_expr(e: Expr, mem: &HashMap<String, EvArray>) -> Result<EvArray, String> {
+/// Reads back a value written with `Display`, such as `[1,[2.5,3]]`.
+impl std::str::FromStr for EvArray {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn read(s: &str) -> Result<(EvArray, &str), String> {
+            if let Some(mut rest) = s.strip_prefix('[') {
+                let mut out = vec![];
+                if let Some(rest) = rest.strip_prefix(']') {
+                    return Ok((EvArray::A(out), rest));
+                }
+                loop {
+                    let (v, r) = read(rest)?;
+                    out.push(v);
+                    match r.chars().next() {
+                        Some(',') => rest = &r[1..],
+                        Some(']') => return Ok((EvArray::A(out), &r[1..])),
+                        _ => return Err(format!("Invalid value '{}'", s)),
+                    }
+                }
+            }
+            let end = s.find([',', ']']).unwrap_or(s.len());
+            match s[..end].parse() {
+                Ok(f) => Ok((EvArray::F(f), &s[end..])),
+                Err(_) => Err(format!("Invalid value '{}'", s)),
+            }
+        }
+
+        match read(s.trim()) {
+            Ok((v, "")) => Ok(v),
+            _ => Err(format!("Invalid value '{}'", s)),
+        }
+    }
+}
+
+fn evaluate_expr(e: Expr, mem: &mut State) -> Result<EvArray, String> {
+    mem.tick(1)?;
+    mem.nested(|mem| evaluate_nested(e, mem))
+}
+
+/// Evaluates `e`, its nesting being already counted.
+fn evaluate_nested(e: Expr, mem: &mut State) -> Result<EvArray, String> {
     match e {
         Expr::Array(v) => {
             let mut out = vec![];
+            let mut len = 0;
             for i in v.into_inner() {
-                out.push(evaluate_expr(i, mem)?);
-            }
-            return Ok(EvArray::A(out));
-        }
-        Expr::Val(v) => return Ok(EvArray::F(v)),
-        Expr::Var(v) => {
-            if mem.contains_key(&v) {
-                return Ok(mem.get(&v).unwrap().clone());
-            } else {
-                return Err(format!("Unknown variable '{}'", v));
+                let i = evaluate_expr(i, mem)?;
+                len += i.deep_len();
+                mem.check_len(len)?;
+                out.push(i);
             }
+            Ok(EvArray::A(out))
         }
+        Expr::Val(v) => Ok(EvArray::F(v)),
+        Expr::Var(v) => match mem.vars.get(&v) {
+            Some(val) => {
+                let val = val.clone();
+                mem.tick(val.deep_len())?;
+                Ok(val)
+            }
+            None => Err(format!("Unknown variable '{}'", v)),
+        },
         Expr::Operation(first, op, second) => match op {
             'd' => {
-                return Ok(dice_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '+' => {
-                return Ok(plus_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '-' => {
-                return Ok(minus_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '*' => {
-                return Ok(times_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '/' => {
-                return Ok(divide_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '^' => {
-                return Ok(power_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '<' => {
-                return Ok(less_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '>' => {
-                return Ok(more_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '=' => {
-                return Ok(equal_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '|' => {
-                return Ok(or_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '&' => {
-                return Ok(and_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            '@' => {
-                return Ok(at_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            'l' => {
-                return Ok(keeplow_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            'h' => {
-                return Ok(keephigh_op(
-                    evaluate_expr(*first, mem)?,
-                    evaluate_expr(*second, mem)?,
-                )?)
-            }
-            'x' => return Ok(x_op(evaluate_expr(*first, mem)?, (*second, mem))?),
-            '_' => return Ok(flatten_op(evaluate_expr(*first, mem)?)?),
-            '!' => return Ok(shallow_flatten_op(evaluate_expr(*first, mem)?)?),
-            's' => return Ok(sum_op(evaluate_expr(*first, mem)?)?),
-
-            e => return Err(format!("Unknown operator '{}'", e)),
+                let first = evaluate_expr(*first, mem)?;
+                let second = evaluate_expr(*second, mem)?;
+                mem.alloc(roll_len(&first, &second))?;
+                let label = format!("{}d{}", first, second);
+                let sides = sides(&second);
+                let out = dice_op(first, second, &mut mem.rng)?;
+                mem.record(|| label, &out, sides);
+                Ok(out)
+            }
+            'r' | 'R' => match unparenthesise(*first) {
+                Expr::Operation(count, 'd', faces) => {
+                    let count = evaluate_expr(*count, mem)?;
+                    let faces = evaluate_expr(*faces, mem)?;
+                    let reroll = evaluate_expr(*second, mem)?;
+                    mem.alloc(roll_len(&count, &faces))?;
+                    let name = if op == 'R' { "rr" } else { "r" };
+                    let label = format!("{}d{}{}{}", count, faces, name, reroll);
+                    let sides = sides(&faces);
+                    let out = reroll_op(count, faces, reroll, op == 'R', mem)?;
+                    mem.record(|| label, &out, sides);
+                    Ok(out)
+                }
+                _ => Err(format!(
+                    "Operator '{}' can only be applied to a dice roll",
+                    if op == 'R' { "rr" } else { "r" }
+                )),
+            },
+            'h' | 'l' => {
+                let recorded = mem.rolls.len();
+                let first = evaluate_expr(*first, mem)?;
+                let second = evaluate_expr(*second, mem)?;
+                mem.record_keep(recorded, &first, op, &second);
+                binary_op(first, op, second)
+            }
+            'x' => x_op(evaluate_expr(*first, mem)?, (*second, mem)),
+            '_' | '!' | 's' => unary_op(evaluate_expr(*first, mem)?, op),
+            _ => binary_op(
+                evaluate_expr(*first, mem)?,
+                op,
+                evaluate_expr(*second, mem)?,
+            ),
         },
-        Expr::None => return Err("Unexpected parse artifact".to_owned()),
+        Expr::None => Err("Unexpected parse artifact".to_owned()),
         Expr::Call(name, args) => {
             let mut parsed_args = vec![];
             for e in args.clone() {
@@ -192,7 +533,7 @@ fn evaluate_expr(e: Expr, mem: &HashMap<String, EvArray>) -> Result<EvArray, Str
                     }
                     match parsed_args[0].clone() {
                         EvArray::F(_) => {
-                            return Ok(EvArray::F((parsed_args[0] == parsed_args[1]) as u8 as f64))
+                            Ok(EvArray::F((parsed_args[0] == parsed_args[1]) as u8 as f64))
                         }
                         EvArray::A(a) => {
                             for i in a {
@@ -200,7 +541,7 @@ fn evaluate_expr(e: Expr, mem: &HashMap<String, EvArray>) -> Result<EvArray, Str
                                     return Ok(EvArray::F(1.));
                                 }
                             }
-                            return Ok(EvArray::F(0.));
+                            Ok(EvArray::F(0.))
                         }
                     }
                 }
@@ -212,22 +553,138 @@ fn evaluate_expr(e: Expr, mem: &HashMap<String, EvArray>) -> Result<EvArray, Str
                         ));
                     }
                     if let EvArray::A(mut a) = parsed_args[0].clone() {
+                        mem.alloc(parsed_args[0].deep_len() + parsed_args[1].deep_len())?;
                         a.push(parsed_args[1].clone());
-                        return Ok(EvArray::A(a));
+                        Ok(EvArray::A(a))
                     } else {
-                        return Err(format!(
+                        Err(format!(
                             "first argument must be an array in call to function '{}'",
                             name
+                        ))
+                    }
+                }
+                "EXPLODE" => {
+                    if !(2..=4).contains(&args.len()) {
+                        return Err(format!(
+                            "invalid number of arguments in call to function '{}'",
+                            name
+                        ));
+                    }
+                    let mut nums = vec![];
+                    for a in parsed_args {
+                        match a {
+                            EvArray::F(f) => nums.push(f),
+                            EvArray::A(_) => {
+                                return Err(format!(
+                                    "arguments must be numbers in call to function '{}'",
+                                    name
+                                ))
+                            }
+                        }
+                    }
+                    // dice explode on their highest side, which is floored as when rolling
+                    let threshold = nums.get(2).copied().unwrap_or(nums[1].floor());
+                    let cap = nums.get(3).copied().unwrap_or(100.);
+                    let out = explode_op(nums[0], nums[1], threshold, cap, mem)?;
+                    let label = || {
+                        let nums = nums.iter().map(f64::to_string).collect::<Vec<_>>();
+                        format!("EXPLODE({})", nums.join(","))
+                    };
+                    mem.record(label, &out, Some((1., nums[1].floor())));
+                    Ok(out)
+                }
+                "SUCCESSES" => {
+                    if !(2..=3).contains(&args.len()) {
+                        return Err(format!(
+                            "invalid number of arguments in call to function '{}'",
+                            name
+                        ));
+                    }
+                    let target = match parsed_args[1] {
+                        EvArray::F(f) => f,
+                        EvArray::A(_) => {
+                            return Err(format!(
+                                "second argument must be a number in call to function '{}'",
+                                name
+                            ))
+                        }
+                    };
+                    let subtract_ones = parsed_args.get(2).is_some_and(|a| a.is_true());
+                    successes_op(parsed_args[0].clone(), target, subtract_ones)
+                }
+                _ => {
+                    let f = match mem.funcs.get(&name) {
+                        Some(f) => f.clone(),
+                        None => return Err(format!("Unknown function : '{}'", name)),
+                    };
+                    if f.params.len() != parsed_args.len() {
+                        return Err(format!(
+                            "invalid number of arguments in call to function '{}'",
+                            name
                         ));
                     }
+                    if mem.depth >= mem.limits.max_depth {
+                        return Err(format!(
+                            "Execution limit exceeded : more than {} nested function calls",
+                            mem.limits.max_depth
+                        ));
+                    }
+                    // the body only sees the global scope and its own parameters
+                    let caller = mem.vars.frames.split_off(1);
+                    mem.vars
+                        .frames
+                        .push(f.params.into_iter().zip(parsed_args).collect());
+                    mem.depth += 1;
+                    let body = f.body;
+                    let res = mem.nested(|mem| run(&body, mem));
+                    mem.depth -= 1;
+                    mem.vars.frames.truncate(1);
+                    mem.vars.frames.extend(caller);
+                    match res? {
+                        Flow::Return(v) => Ok(v),
+                        Flow::Next => Ok(EvArray::A(vec![])),
+                        Flow::Break | Flow::Continue => Err(format!(
+                            "'break' or 'continue' outside of a loop in function '{}'",
+                            name
+                        )),
+                    }
                 }
-                _ => Err(format!("Unknown function : '{}'", name)),
             }
         }
         Expr::Parenthesised(e) => evaluate_expr(*e, mem),
     }
 }
 
+/// Applies one of the unary operators, none of which involves randomness.
+pub fn unary_op(operand: EvArray, op: char) -> Result<EvArray, String> {
+    match op {
+        '_' => flatten_op(operand),
+        '!' => shallow_flatten_op(operand),
+        's' => sum_op(operand),
+        e => Err(format!("Unknown operator '{}'", e)),
+    }
+}
+
+/// Applies one of the binary operators that do not involve randomness.
+pub fn binary_op(first: EvArray, op: char, second: EvArray) -> Result<EvArray, String> {
+    match op {
+        '+' => plus_op(first, second),
+        '-' => minus_op(first, second),
+        '*' => times_op(first, second),
+        '/' => divide_op(first, second),
+        '^' => power_op(first, second),
+        '<' => less_op(first, second),
+        '>' => more_op(first, second),
+        '=' => equal_op(first, second),
+        '|' => or_op(first, second),
+        '&' => and_op(first, second),
+        '@' => at_op(first, second),
+        'l' => keeplow_op(first, second),
+        'h' => keephigh_op(first, second),
+        e => Err(format!("Unknown operator '{}'", e)),
+    }
+}
+
 fn sum_op(operand: EvArray) -> Result<EvArray, String> {
     match operand {
         EvArray::A(a) => {
@@ -236,7 +693,7 @@ fn sum_op(operand: EvArray) -> Result<EvArray, String> {
                 if let EvArray::F(f) = i {
                     out += f
                 } else {
-                    return Err(format!("Impossible to sum an array containing arrays. Consider to flatten the array with '_'."));
+                    return Err("Impossible to sum an array containing arrays. Consider to flatten the array with '_'.".to_string());
                 }
             }
             Ok(EvArray::F(out))
@@ -280,21 +737,19 @@ fn keeplow_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
     match (first, second) {
         (EvArray::A(a), EvArray::F(f)) => {
             if f < 0. {
-                return Err(format!("Rhs cannot be negative in call to operator 'l'"));
+                return Err("Rhs cannot be negative in call to operator 'l'".to_string());
             }
             if f as usize > a.len() {
-                return Err(format!(
-                    "Rhs is to large compared to array in call to operator 'l'"
-                ));
+                return Err("Rhs is to large compared to array in call to operator 'l'".to_string());
             }
             let mut flts = vec![];
             for i in a {
                 if let EvArray::F(f) = i {
                     flts.push(f);
                 } else {
-                    return Err(format!(
-                        "Array cannot include non floats in call to operator 'l'"
-                    ));
+                    return Err(
+                        "Array cannot include non floats in call to operator 'l'".to_string()
+                    );
                 }
             }
             let mut out = vec![];
@@ -305,9 +760,7 @@ fn keeplow_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
             }
             Ok(EvArray::A(out))
         }
-        _ => Err(format!(
-            "Operator 'l' only accepts <array>l<number> as operands"
-        )),
+        _ => Err("Operator 'l' only accepts <array>l<number> as operands".to_string()),
     }
 }
 
@@ -315,21 +768,19 @@ fn keephigh_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
     match (first, second) {
         (EvArray::A(a), EvArray::F(f)) => {
             if f < 0. {
-                return Err(format!("Rhs cannot be negative in call to operator 'h'"));
+                return Err("Rhs cannot be negative in call to operator 'h'".to_string());
             }
             if f as usize > a.len() {
-                return Err(format!(
-                    "Rhs is to large compared to array in call to operator 'h'"
-                ));
+                return Err("Rhs is to large compared to array in call to operator 'h'".to_string());
             }
             let mut flts = vec![];
             for i in a {
                 if let EvArray::F(f) = i {
                     flts.push(f);
                 } else {
-                    return Err(format!(
-                        "Array cannot include non floats in call to operator 'h'"
-                    ));
+                    return Err(
+                        "Array cannot include non floats in call to operator 'h'".to_string()
+                    );
                 }
             }
             let mut out = vec![];
@@ -341,18 +792,20 @@ fn keephigh_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
             }
             Ok(EvArray::A(out))
         }
-        _ => Err(format!(
-            "Operator 'h' only accepts <array>l<number> as operands"
-        )),
+        _ => Err("Operator 'h' only accepts <array>l<number> as operands".to_string()),
     }
 }
 
-fn x_op(first: EvArray, second: (Expr, &HashMap<String, EvArray>)) -> Result<EvArray, String> {
+fn x_op(first: EvArray, second: (Expr, &mut State)) -> Result<EvArray, String> {
     match first {
         EvArray::F(f) => {
+            second.1.check_len(f as usize)?;
             let mut out = vec![];
+            let mut len = 0;
             for _ in 0..(f as usize) {
                 let s = evaluate_expr(second.0.clone(), second.1)?;
+                len += s.deep_len();
+                second.1.check_len(len)?;
                 out.push(s)
             }
             Ok(EvArray::A(out))
@@ -360,6 +813,8 @@ fn x_op(first: EvArray, second: (Expr, &HashMap<String, EvArray>)) -> Result<EvA
         EvArray::A(a) => {
             let s = evaluate_expr(second.0, second.1)?;
             if let EvArray::F(f) = s {
+                let len = a.iter().map(|i| i.deep_len()).sum::<usize>();
+                second.1.alloc(len.saturating_mul(f as usize))?;
                 let mut out = vec![];
                 for _ in 0..(f as usize) {
                     for i in a.clone() {
@@ -369,7 +824,7 @@ fn x_op(first: EvArray, second: (Expr, &HashMap<String, EvArray>)) -> Result<EvA
 
                 Ok(EvArray::A(out))
             } else {
-                Err(format!("Cannot infer duplication number from array"))
+                Err("Cannot infer duplication number from array".to_string())
             }
         }
     }
@@ -379,17 +834,17 @@ fn at_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
     if let EvArray::F(f) = first {
         if let EvArray::A(a) = second {
             if f < 0. {
-                Err(format!("Impossible to index with a negative number"))
+                Err("Impossible to index with a negative number".to_string())
             } else if f as usize >= a.len() {
-                Err(format!("Index larger than array length"))
+                Err("Index larger than array length".to_string())
             } else {
                 Ok(a[f as usize].clone())
             }
         } else {
-            Err(format!("Impossible to index a float"))
+            Err("Impossible to index a float".to_string())
         }
     } else {
-        Err(format!("Impossibe to index with an array"))
+        Err("Impossibe to index with an array".to_string())
     }
 }
 
@@ -398,10 +853,10 @@ fn and_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         if let EvArray::F(s) = second {
             Ok(EvArray::F(if f != 0. && s != 0. { 1. } else { 0. }))
         } else {
-            Err(format!("Logical operators cannot be used on arrays"))
+            Err("Logical operators cannot be used on arrays".to_string())
         }
     } else {
-        Err(format!("Logical operators cannot be used on arrays"))
+        Err("Logical operators cannot be used on arrays".to_string())
     }
 }
 
@@ -410,10 +865,10 @@ fn or_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         if let EvArray::F(s) = second {
             Ok(EvArray::F(if f != 0. || s != 0. { 1. } else { 0. }))
         } else {
-            Err(format!("Logical operators cannot be used on arrays"))
+            Err("Logical operators cannot be used on arrays".to_string())
         }
     } else {
-        Err(format!("Logical operators cannot be used on arrays"))
+        Err("Logical operators cannot be used on arrays".to_string())
     }
 }
 
@@ -444,10 +899,10 @@ fn more_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         if let EvArray::F(s) = second {
             Ok(EvArray::F(if f > s { 1. } else { 0. }))
         } else {
-            Err(format!("Logical operators cannot be used on arrays"))
+            Err("Logical operators cannot be used on arrays".to_string())
         }
     } else {
-        Err(format!("Logical operators cannot be used on arrays"))
+        Err("Logical operators cannot be used on arrays".to_string())
     }
 }
 
@@ -456,10 +911,10 @@ fn less_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         if let EvArray::F(s) = second {
             Ok(EvArray::F(if f < s { 1. } else { 0. }))
         } else {
-            Err(format!("Logical operators cannot be used on arrays"))
+            Err("Logical operators cannot be used on arrays".to_string())
         }
     } else {
-        Err(format!("Logical operators cannot be used on arrays"))
+        Err("Logical operators cannot be used on arrays".to_string())
     }
 }
 
@@ -482,9 +937,9 @@ fn power_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         }
         (EvArray::A(f), EvArray::A(s)) => {
             if f.len() != s.len() {
-                return Err(format!(
-                    "Cannot use mathematical operators on differently sized arrays"
-                ));
+                return Err(
+                    "Cannot use mathematical operators on differently sized arrays".to_string(),
+                );
             }
             let mut out = vec![];
             for i in 0..f.len() {
@@ -499,7 +954,7 @@ fn divide_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
     match (first, second) {
         (EvArray::F(f), EvArray::F(s)) => {
             if s == 0. {
-                Err(format!("Cannot divide by 0"))
+                Err("Cannot divide by 0".to_string())
             } else {
                 Ok(EvArray::F(f / s))
             }
@@ -520,9 +975,9 @@ fn divide_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         }
         (EvArray::A(f), EvArray::A(s)) => {
             if f.len() != s.len() {
-                return Err(format!(
-                    "Cannot use mathematical operators on differently sized arrays"
-                ));
+                return Err(
+                    "Cannot use mathematical operators on differently sized arrays".to_string(),
+                );
             }
             let mut out = vec![];
             for i in 0..f.len() {
@@ -552,9 +1007,9 @@ fn times_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         }
         (EvArray::A(f), EvArray::A(s)) => {
             if f.len() != s.len() {
-                return Err(format!(
-                    "Cannot use mathematical operators on differently sized arrays"
-                ));
+                return Err(
+                    "Cannot use mathematical operators on differently sized arrays".to_string(),
+                );
             }
             let mut out = vec![];
             for i in 0..f.len() {
@@ -584,9 +1039,9 @@ fn minus_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         }
         (EvArray::A(f), EvArray::A(s)) => {
             if f.len() != s.len() {
-                return Err(format!(
-                    "Cannot use mathematical operators on differently sized arrays"
-                ));
+                return Err(
+                    "Cannot use mathematical operators on differently sized arrays".to_string(),
+                );
             }
             let mut out = vec![];
             for i in 0..f.len() {
@@ -616,9 +1071,9 @@ fn plus_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
         }
         (EvArray::A(f), EvArray::A(s)) => {
             if f.len() != s.len() {
-                return Err(format!(
-                    "Cannot use mathematical operators on differently sized arrays"
-                ));
+                return Err(
+                    "Cannot use mathematical operators on differently sized arrays".to_string(),
+                );
             }
             let mut out = vec![];
             for i in 0..f.len() {
@@ -629,11 +1084,16 @@ fn plus_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
     }
 }
 
-fn dice_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
+fn dice_op(first: EvArray, second: EvArray, rng: &mut impl Rng) -> Result<EvArray, String> {
     match (first, second) {
+        // NaN sides would otherwise get past the comparison
+        (EvArray::F(_), EvArray::F(s)) if !s.is_finite() || s < 1. => {
+            Err("Dice need at least one side in call to operator 'd'".to_string())
+        }
+        (EvArray::F(_), EvArray::A(s)) if s.is_empty() => {
+            Err("Dice need at least one side in call to operator 'd'".to_string())
+        }
         (EvArray::F(f), EvArray::F(s)) => {
-            let mut rng = thread_rng();
-
             let mut out = vec![];
 
             for _ in 0..(f as usize) {
@@ -644,55 +1104,277 @@ fn dice_op(first: EvArray, second: EvArray) -> Result<EvArray, String> {
             Ok(EvArray::A(out))
         }
         (EvArray::F(f), EvArray::A(s)) => {
-            let mut rng = thread_rng();
-
             let mut out = vec![];
 
             for _ in 0..(f as usize) {
-                let n: usize = rng.gen_range(0..(s.len() as usize));
+                let n: usize = rng.gen_range(0..s.len());
                 out.push(s[n].clone());
             }
 
             Ok(EvArray::A(out))
         }
-        (EvArray::A(_), _) => Err(format!(
-            "Cannot infer the number of dice throws from an array"
-        )),
+        (EvArray::A(_), _) => {
+            Err("Cannot infer the number of dice throws from an array".to_string())
+        }
     }
 }
 
-pub fn evaluate(t: &Vec<Stmt>, mem: &mut HashMap<String, EvArray>) -> Result<String, String> {
-    let mut out: String = String::new();
+pub fn unparenthesise(e: Expr) -> Expr {
+    match e {
+        Expr::Parenthesised(e) => unparenthesise(*e),
+        e => e,
+    }
+}
+
+/// Upper bound on the number of values a `count`d`faces` roll produces.
+fn roll_len(count: &EvArray, faces: &EvArray) -> usize {
+    match (count, faces) {
+        (EvArray::F(f), EvArray::A(a)) => {
+            (*f as usize).saturating_mul(a.iter().map(|i| i.deep_len()).max().unwrap_or(1))
+        }
+        (EvArray::F(f), EvArray::F(_)) => *f as usize,
+        (EvArray::A(_), _) => 0,
+    }
+}
+
+/// Whether a die showing `v` is rerolled by the `r` and `rr` operators with `reroll` as rhs.
+pub fn reroll_matches(reroll: &EvArray, v: &EvArray) -> bool {
+    match (reroll, v) {
+        (EvArray::F(r), EvArray::F(v)) => v <= r,
+        (EvArray::A(r), v) => r.contains(v),
+        (EvArray::F(_), EvArray::A(_)) => false,
+    }
+}
+
+/// Whether no side of a die with `faces` escapes being rerolled by `reroll`.
+pub fn rerolls_every_side(faces: &EvArray, reroll: &EvArray) -> bool {
+    match (faces, reroll) {
+        (EvArray::F(s), EvArray::F(r)) => *r >= s.floor(),
+        (EvArray::F(s), EvArray::A(r)) => (1..=(*s as usize))
+            .take(r.len() + 1)
+            .all(|n| r.contains(&EvArray::F(n as f64))),
+        (EvArray::A(s), _) => s.iter().all(|v| reroll_matches(reroll, v)),
+    }
+}
+
+/// Rolls like `dice_op`, then rerolls every die matching `reroll`, meaning any value up to
+/// it or any value it contains if it is an array. Dice are rerolled once, or as long as they
+/// match (at most 1000 times per die) if `repeat` is set. Every reroll counts against the
+/// steps of `mem`.
+fn reroll_op(
+    first: EvArray,
+    second: EvArray,
+    reroll: EvArray,
+    repeat: bool,
+    mem: &mut State,
+) -> Result<EvArray, String> {
+    let name = if repeat { "rr" } else { "r" };
+    let f = match first {
+        EvArray::F(f) => f,
+        EvArray::A(_) => {
+            return Err("Cannot infer the number of dice throws from an array".to_string())
+        }
+    };
+    match &second {
+        EvArray::F(s) if !s.is_finite() || *s < 1. => {
+            return Err(format!(
+                "Dice need at least one side in call to operator '{}'",
+                name
+            ))
+        }
+        EvArray::A(s) if s.is_empty() => {
+            return Err(format!(
+                "Dice need at least one side in call to operator '{}'",
+                name
+            ))
+        }
+        _ => {}
+    }
+
+    let matches = |v: &EvArray| reroll_matches(&reroll, v);
+    if repeat && rerolls_every_side(&second, &reroll) {
+        return Err(format!(
+            "Every side of the dice would be rerolled in call to operator '{}'",
+            name
+        ));
+    }
+
+    let roll = |rng: &mut ChaCha8Rng| match &second {
+        EvArray::F(s) => EvArray::F(rng.gen_range(1..=(*s as usize)) as f64),
+        EvArray::A(s) => s[rng.gen_range(0..s.len())].clone(),
+    };
+
+    let mut out = vec![];
+
+    for _ in 0..(f as usize) {
+        let mut n = roll(&mut mem.rng);
+        let mut rerolls = 0;
+        while matches(&n) && rerolls < if repeat { 1000 } else { 1 } {
+            mem.tick(1)?;
+            n = roll(&mut mem.rng);
+            rerolls += 1;
+        }
+        out.push(n);
+    }
+
+    Ok(EvArray::A(out))
+}
+
+/// Counts the dice of `rolls` showing `target` or more, each 1 cancelling a success if
+/// `subtract_ones` is set. Returns `[successes, botch, rolls]`, where `botch` is 1 when no
+/// die reached the target but at least one shows a 1.
+pub fn successes_op(rolls: EvArray, target: f64, subtract_ones: bool) -> Result<EvArray, String> {
+    let dice = match &rolls {
+        EvArray::F(f) => vec![*f],
+        EvArray::A(a) => {
+            let mut dice = vec![];
+            for i in a {
+                if let EvArray::F(f) = i {
+                    dice.push(*f);
+                } else {
+                    return Err("Array cannot include non floats in call to function 'SUCCESSES'. Consider to flatten the array with '_'.".to_string());
+                }
+            }
+            dice
+        }
+    };
+    let hits = dice.iter().filter(|d| **d >= target).count() as f64;
+    let ones = dice.iter().filter(|d| **d == 1.).count() as f64;
+
+    let count = if subtract_ones {
+        (hits - ones).max(0.)
+    } else {
+        hits
+    };
+    let botch = hits == 0. && ones > 0.;
 
+    Ok(EvArray::A(vec![
+        EvArray::F(count),
+        EvArray::F(botch as u8 as f64),
+        rolls,
+    ]))
+}
+
+/// Most explosions a die can have, whatever the cap given to `EXPLODE`.
+const MAX_EXPLOSIONS: f64 = 1000.;
+
+/// Rolls `count` dice with `sides` faces, rolling one more die every time a die
+/// shows `threshold` or more, at most `cap` times per initial die. Every die counts
+/// against the limits of `mem`.
+fn explode_op(
+    count: f64,
+    sides: f64,
+    threshold: f64,
+    cap: f64,
+    mem: &mut State,
+) -> Result<EvArray, String> {
+    if !sides.is_finite() || sides < 1. {
+        return Err("Dice need at least one side in call to function 'EXPLODE'".to_string());
+    }
+    if threshold.is_nan() || threshold <= 1. {
+        return Err("Threshold must be larger than 1 in call to function 'EXPLODE'".to_string());
+    }
+    let cap = cap.min(MAX_EXPLOSIONS);
+    mem.check_len(count as usize)?;
+    let mut out = vec![];
+
+    for _ in 0..(count as usize) {
+        let mut explosions = 0;
+        loop {
+            mem.check_len(out.len() + 1)?;
+            mem.tick(1)?;
+            let n: usize = mem.rng.gen_range(1..=(sides as usize));
+            out.push(EvArray::F(n as f64));
+            if (n as f64) < threshold || explosions as f64 >= cap {
+                break;
+            }
+            explosions += 1;
+        }
+    }
+
+    Ok(EvArray::A(out))
+}
+
+pub fn evaluate(t: &[Stmt], mem: &mut State) -> Result<String, String> {
+    mem.steps = 0;
+    mem.depth = 0;
+    mem.nesting = 0;
+    mem.last = None;
+    let mut res = Ok(Flow::Next);
+    for (i, stmt) in t.iter().enumerate() {
+        mem.stmt = i;
+        res = run(std::slice::from_ref(stmt), mem);
+        trace!(statement = i, steps = mem.steps, result = ?res, "evaluated");
+        if !matches!(res, Ok(Flow::Next)) {
+            break;
+        }
+    }
+    let out = std::mem::take(&mut mem.out);
+    // an error may leave the scopes of the blocks it escaped behind
+    mem.vars.frames.truncate(1);
+    if let Flow::Break | Flow::Continue = res? {
+        return Err("'break' or 'continue' outside of a loop".to_owned());
+    }
+    Ok(out)
+}
+
+/// Runs `t` in a new scope, optionally binding a loop variable in it first.
+fn run_block(
+    t: &[Stmt],
+    mem: &mut State,
+    local: Option<(String, EvArray)>,
+) -> Result<Flow, String> {
+    mem.vars.push();
+    if let Some((name, val)) = local {
+        mem.vars.define(name, val);
+    }
+    let res = mem.nested(|mem| run(t, mem));
+    mem.vars.pop();
+    res
+}
+
+fn run(t: &[Stmt], mem: &mut State) -> Result<Flow, String> {
     let mut i = 0;
 
     while i < t.len() {
+        mem.tick(1)?;
         let c = t[i].clone();
         match c {
             Stmt::Bind(v, val) => {
-                mem.insert(v, evaluate_expr(val, &mem)?);
+                let val = evaluate_expr(val, mem)?;
+                mem.vars.set(v, val);
             }
             Stmt::Out(e) => {
-                let o = evaluate_expr(e, &mem)?;
-                out = format!("{}{}\n", out, o);
+                let o = evaluate_expr(e, mem)?;
+                mem.print(&o)?;
+                mem.last = Some(o);
             }
             Stmt::StringOut(e) => {
-                let o = evaluate_expr(e, &mem)?;
-                out = format!("{}{}\n", out, o.stringify()?);
+                let o = evaluate_expr(e, mem)?;
+                mem.print(o.stringify()?)?;
             }
             Stmt::Condition(e, Some(ife), el) => {
-                if evaluate_expr(e, &mem)?.is_true() {
-                    out = format!("{}{}", out, evaluate(&ife, mem)?);
+                let flow = if evaluate_expr(e, mem)?.is_true() {
+                    run_block(&ife, mem, None)?
                 } else if let Some(els) = el {
-                    out = format!("{}{}", out, evaluate(&els, mem)?);
+                    run_block(&els, mem, None)?
+                } else {
+                    Flow::Next
+                };
+                if !matches!(flow, Flow::Next) {
+                    return Ok(flow);
                 }
             }
             Stmt::Condition(_, _, _) => {
                 return Err(format!("Error : malformed condition at index {}", i));
             }
             Stmt::While(e, Some(bod)) => {
-                while evaluate_expr(e.clone(), &mem)?.is_true() {
-                    out = format!("{}{}", out, evaluate(&bod, mem)?);
+                while evaluate_expr(e.clone(), mem)?.is_true() {
+                    match run_block(&bod, mem, None)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        Flow::Next | Flow::Continue => {}
+                    }
                 }
             }
             Stmt::While(_, _) => {
@@ -702,18 +1384,35 @@ pub fn evaluate(t: &Vec<Stmt>, mem: &mut HashMap<String, EvArray>) -> Result<Str
                 let es = evaluate_expr(e, mem)?;
 
                 if let EvArray::F(_) = es {
-                    mem.insert(v, es);
-                    out = format!("{}{}", out, evaluate(&bod, mem)?);
+                    if let Flow::Return(v) = run_block(&bod, mem, Some((v, es)))? {
+                        return Ok(Flow::Return(v));
+                    }
                 } else if let EvArray::A(a) = es {
                     for i in a {
-                        mem.insert(v.clone(), i);
-                        out = format!("{}{}", out, evaluate(&bod, mem)?);
+                        match run_block(&bod, mem, Some((v.clone(), i)))? {
+                            Flow::Break => break,
+                            Flow::Return(v) => return Ok(Flow::Return(v)),
+                            Flow::Next | Flow::Continue => {}
+                        }
                     }
                 }
             }
             Stmt::For(_, _, _) => {
                 return Err(format!("Error : malformed for loop at index {}", i));
             }
+            Stmt::Function(Some(name), Some(params), Some(body)) => {
+                mem.funcs.insert(name, Function { params, body });
+            }
+            Stmt::Function(_, _, _) => {
+                return Err(format!(
+                    "Error : malformed function definition at index {}",
+                    i
+                ));
+            }
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Continue => return Ok(Flow::Continue),
+            Stmt::Return(Expr::None) => return Ok(Flow::Return(EvArray::A(vec![]))),
+            Stmt::Return(e) => return Ok(Flow::Return(evaluate_expr(e, mem)?)),
             Stmt::None => {
                 return Err(format!("Error : unexpected parser artifact at index {}", i));
             }
@@ -721,5 +1420,266 @@ pub fn evaluate(t: &Vec<Stmt>, mem: &mut HashMap<String, EvArray>) -> Result<Str
         i += 1;
     }
 
-    return Ok(out);
+    Ok(Flow::Next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    const SEED: u64 = 42;
+
+    fn run(program: &str) -> Result<String, String> {
+        Interpreter::seeded(Limits::default(), SEED)
+            .eval(program)
+            .map(|o| o.text)
+            .map_err(|e| e.message().to_owned())
+    }
+
+    /// Dice output by `program`.
+    fn dice(program: &str) -> Vec<EvArray> {
+        match Interpreter::seeded(Limits::default(), SEED)
+            .eval(program)
+            .unwrap()
+            .value
+        {
+            Some(EvArray::A(dice)) => dice,
+            v => panic!("unexpected output {:?}", v),
+        }
+    }
+
+    #[test]
+    fn seeded_dice() {
+        assert_eq!(run("$4d6").unwrap(), "[5,6,3,2]\n");
+        assert_eq!(run("$4d6h3s").unwrap(), "14\n");
+        assert_eq!(run("for D 3d6 {$D*2}").unwrap(), "10\n12\n6\n");
+    }
+
+    #[test]
+    fn display() {
+        let v: EvArray = "[1,[2.5,3],[]]".parse().unwrap();
+        assert_eq!(v.to_string(), "[1,[2.5,3],[]]");
+        assert_eq!(EvArray::A(vec![]).to_string(), "[]");
+        assert_eq!(run("$100000d1").unwrap().len(), 2 * 100_000 + 2);
+    }
+
+    #[test]
+    fn reseeding_rolls_the_same_dice() {
+        let mut interpreter = Interpreter::seeded(Limits::default(), SEED);
+        let first = interpreter.eval("$10d20").unwrap().text;
+        assert_ne!(interpreter.eval("$10d20").unwrap().text, first);
+        interpreter.reseed(SEED);
+        assert_eq!(interpreter.eval("$10d20").unwrap().text, first);
+    }
+
+    #[test]
+    fn dice_stay_within_their_sides() {
+        let rolled = dice("$1000d6");
+        assert_eq!(rolled.len(), 1000);
+        assert!(rolled
+            .iter()
+            .all(|d| matches!(d, EvArray::F(f) if (1. ..=6.).contains(f))));
+    }
+
+    #[test]
+    fn programs() {
+        assert_eq!(run("$1+2*3").unwrap(), "7\n");
+        assert_eq!(
+            run("fn DOUBLE(X) { return X*2 } $DOUBLE(21)").unwrap(),
+            "42\n"
+        );
+        assert_eq!(run("if1 {$1} else {$2}").unwrap(), "1\n");
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(run("V=1 while V<4 {$V V=V+1}").unwrap(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(
+            run("for D [1,2] {X=D} $X").unwrap_err(),
+            "Unknown variable 'X'"
+        );
+        assert_eq!(
+            run("for D [1,2] {} $D").unwrap_err(),
+            "Unknown variable 'D'"
+        );
+        assert_eq!(run("X=1 if 1 {X=2} $X").unwrap(), "2\n");
+        assert_eq!(
+            run("fn F() { Y=1 return Y } $F() $Y").unwrap_err(),
+            "Unknown variable 'Y'"
+        );
+        assert_eq!(
+            run("fn F() { X=3 return X } X=1 $F() $X").unwrap(),
+            "3\n3\n"
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        assert_eq!(
+            run("for D [1,2,3,4] { if D=2 {continue} if D=4 {break} $D }").unwrap(),
+            "1\n3\n"
+        );
+        assert_eq!(
+            run("V=0 while 1 { V=V+1 if V>3 {break} } $V").unwrap(),
+            "4\n"
+        );
+        assert_eq!(
+            run("fn F() { for D [1,2,3] { if D=2 {return D*10} } return 0 } $F()").unwrap(),
+            "20\n"
+        );
+        assert_eq!(
+            run("break").unwrap_err(),
+            "'break' or 'continue' outside of a loop"
+        );
+        assert_eq!(
+            run("fn F() {continue} $F()").unwrap_err(),
+            "'break' or 'continue' outside of a loop in function 'F'"
+        );
+    }
+
+    #[test]
+    fn explode() {
+        // every die showing the maximum is followed by the die it exploded into
+        let rolled = dice("$EXPLODE(200, 2)");
+        let stopped = rolled.iter().filter(|d| **d == EvArray::F(1.)).count();
+        assert_eq!(stopped, 200);
+        assert!(rolled.len() > 200);
+        assert_eq!(rolled.last(), Some(&EvArray::F(1.)));
+
+        // the sides are floored, so that 2.5-sided dice explode on a 2
+        assert!(dice("$EXPLODE(200, 2.5)").len() > 200);
+
+        assert_eq!(dice("$EXPLODE(200, 2, 2, 0)").len(), 200);
+        let capped = dice("$EXPLODE(200, 2, 2, 1)").len();
+        assert!(capped > 200 && capped <= 400, "{}", capped);
+    }
+
+    #[test]
+    fn explode_errors() {
+        for program in [
+            "$EXPLODE(1, 0)",
+            "$EXPLODE(1, (0-1)^0.5)",
+            "$EXPLODE(1, 10^400)",
+        ] {
+            assert_eq!(
+                run(program).unwrap_err(),
+                "Dice need at least one side in call to function 'EXPLODE'",
+                "{}",
+                program
+            );
+        }
+        assert_eq!(
+            run("$EXPLODE(1, 6, 1)").unwrap_err(),
+            "Threshold must be larger than 1 in call to function 'EXPLODE'"
+        );
+        assert_eq!(
+            run("$EXPLODE(1, 6, (0-1)^0.5)").unwrap_err(),
+            "Threshold must be larger than 1 in call to function 'EXPLODE'"
+        );
+    }
+
+    #[test]
+    fn reroll() {
+        let all = |dice: &[EvArray], values: &[f64]| {
+            dice.iter()
+                .all(|d| values.iter().any(|v| *d == EvArray::F(*v)))
+        };
+
+        // a single reroll can show a 1 again, but far less often than a 2
+        let once = dice("$400d2r1");
+        let ones = once.iter().filter(|d| **d == EvArray::F(1.)).count();
+        assert_eq!(once.len(), 400);
+        assert!(ones > 0 && ones < 200, "{}", ones);
+
+        let repeated = dice("$400d6rr2");
+        assert_eq!(repeated.len(), 400);
+        assert!(all(&repeated, &[3., 4., 5., 6.]));
+
+        assert!(all(&dice("$400d6rr[1,2]"), &[3., 4., 5., 6.]));
+        assert!(all(&dice("$400d[1,5,9]rr[1]"), &[5., 9.]));
+
+        // rerolls take steps like the dice they replace
+        assert!(run("$100000d1000000rr999999s")
+            .unwrap_err()
+            .starts_with("Execution limit exceeded"));
+    }
+
+    #[test]
+    fn reroll_errors() {
+        for program in ["$2d6rr6", "$2d3rr[1,2,3]", "$2d[1,2]rr[1,2]"] {
+            assert_eq!(
+                run(program).unwrap_err(),
+                "Every side of the dice would be rerolled in call to operator 'rr'",
+                "{}",
+                program
+            );
+        }
+        assert_eq!(
+            run("$2d((0-1)^0.5)r1").unwrap_err(),
+            "Dice need at least one side in call to operator 'r'"
+        );
+        assert_eq!(
+            run("$2d0rr1").unwrap_err(),
+            "Dice need at least one side in call to operator 'rr'"
+        );
+    }
+
+    #[test]
+    fn successes() {
+        assert_eq!(
+            run("$SUCCESSES([8,9,1,3,10], 8)").unwrap(),
+            "[3,0,[8,9,1,3,10]]\n"
+        );
+        assert_eq!(
+            run("$SUCCESSES([8,9,1,3,10], 8, 1)").unwrap(),
+            "[2,0,[8,9,1,3,10]]\n"
+        );
+        // ones cancelling every success is no botch, unlike missing every die
+        assert_eq!(
+            run("$SUCCESSES([8,1,1,1], 8, 1)").unwrap(),
+            "[0,0,[8,1,1,1]]\n"
+        );
+        assert_eq!(run("$SUCCESSES([2,1,3], 8)").unwrap(), "[0,1,[2,1,3]]\n");
+        assert_eq!(run("$SUCCESSES(4, 3)").unwrap(), "[1,0,4]\n");
+        assert!(run("$SUCCESSES([[1]], 8)")
+            .unwrap_err()
+            .starts_with("Array cannot include non floats"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            run("$1d0").unwrap_err(),
+            "Dice need at least one side in call to operator 'd'"
+        );
+        assert_eq!(
+            run("$1d((0-1)^0.5)").unwrap_err(),
+            "Dice need at least one side in call to operator 'd'"
+        );
+        assert_eq!(run("$A").unwrap_err(), "Unknown variable 'A'");
+        assert!(run("V=0 while 1 {V=V+1}")
+            .unwrap_err()
+            .starts_with("Execution limit exceeded"));
+    }
+    #[test]
+    fn nesting() {
+        let limits = Limits {
+            max_nesting: 40,
+            ..Limits::default()
+        };
+        let program = |n| format!("fn F(N) {{if N {{return F(N-1)+1}} return 0}} $F({})", n);
+        let mut interpreter = Interpreter::seeded(limits, SEED);
+        assert_eq!(interpreter.eval(&program(3)).unwrap().text, "3\n");
+        assert_eq!(
+            interpreter.eval(&program(20)).unwrap_err().message(),
+            "Execution limit exceeded : expressions and blocks nested more than 40 deep"
+        );
+        // nesting is counted afresh for every program
+        assert_eq!(interpreter.eval(&program(3)).unwrap().text, "3\n");
+    }
 }
@@ -0,0 +1,182 @@
+use crate::{has_permission, Handler, Origin};
+use dicer_rs::evaluator::DiceRoll;
+use serenity::client::Context;
+use serenity::model::id::{GuildId, RoleId, UserId};
+use serenity::model::Permissions;
+use tracing::warn;
+
+/// Guild setting holding the id of the role whose members see secret rolls.
+const ROLE_KEY: &str = "gm_role";
+
+/// Sets the GM role of the guild from `args`, a role mention or id, or `off` to remove it.
+/// This takes the Manage Server permission.
+pub async fn set_role(handler: &Handler, ctx: &Context, origin: &Origin, args: &str) -> String {
+    let guild = match origin.guild {
+        Some(g) => g,
+        None => return "There is no guild here".to_owned(),
+    };
+    match has_permission(ctx, guild, origin.user, Permissions::MANAGE_GUILD).await {
+        Ok(true) => {}
+        Ok(false) => return "Setting the GM role takes the Manage Server permission".to_owned(),
+        Err(why) => return format!("Unable to check your permissions : {}", why),
+    }
+
+    let args = args.trim();
+    let role = if args == "off" {
+        None
+    } else {
+        let id = args.trim_start_matches("<@&").trim_end_matches('>');
+        match id.parse::<u64>() {
+            Ok(id) => Some(id.to_string()),
+            Err(_) => return format!("Invalid role '{}'", args),
+        }
+    };
+    match handler
+        .storage
+        .set_setting(guild.0, ROLE_KEY, role.as_deref())
+    {
+        Ok(()) if role.is_some() => format!("Secret rolls are now shown to {}", args),
+        Ok(()) => "Secret rolls are now only shown to who rolled them".to_owned(),
+        Err(e) => e,
+    }
+}
+
+/// GM role of `guild`, if it has one.
+fn role(handler: &Handler, guild: GuildId) -> Result<Option<RoleId>, String> {
+    match handler.storage.setting(guild.0, ROLE_KEY)? {
+        Some(r) => Ok(Some(RoleId(
+            r.parse().map_err(|_| format!("invalid GM role '{}'", r))?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Checks that the user of `origin` may change the `what`, such as the macros, of the guild,
+/// which takes the Manage Server permission or the GM role.
+pub async fn check_manager(
+    handler: &Handler,
+    ctx: &Context,
+    origin: &Origin,
+    what: &str,
+) -> Result<(), String> {
+    let guild = origin
+        .guild
+        .ok_or_else(|| "There is no guild here".to_owned())?;
+    match has_permission(ctx, guild, origin.user, Permissions::MANAGE_GUILD).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(why) => return Err(format!("Unable to check your permissions : {}", why)),
+    }
+    if let Some(role) = role(handler, guild)? {
+        let member = guild
+            .member(&ctx.http, origin.user)
+            .await
+            .map_err(|e| format!("Unable to check your roles : {}", e))?;
+        if member.roles.contains(&role) {
+            return Ok(());
+        }
+    }
+    Err(format!(
+        "Changing the {} of the server takes the Manage Server permission or the GM role",
+        what
+    ))
+}
+
+/// Members of the GM role of the guild `origin` is in, if it has one. Listing them takes the
+/// Server Members intent to be enabled for the bot.
+async fn gms(handler: &Handler, ctx: &Context, origin: &Origin) -> Result<Vec<UserId>, String> {
+    let guild = match origin.guild {
+        Some(g) => g,
+        None => return Ok(vec![]),
+    };
+    let role = match role(handler, guild)? {
+        Some(r) => r,
+        None => return Ok(vec![]),
+    };
+
+    let mut out = vec![];
+    let mut after = None;
+    loop {
+        let members = guild
+            .members(&ctx.http, Some(1000), after)
+            .await
+            .map_err(|e| format!("unable to list the GMs : {}", e))?;
+        after = members.last().map(|m| m.user.id);
+        out.extend(
+            members
+                .iter()
+                .filter(|m| m.roles.contains(&role))
+                .map(|m| m.user.id),
+        );
+        if members.len() < 1000 {
+            return Ok(out);
+        }
+    }
+}
+
+/// Sends the output of a secret roll to the roller and to the GMs by direct message, and
+/// returns the notice to show in the channel instead.
+pub async fn send(
+    handler: &Handler,
+    ctx: &Context,
+    origin: &Origin,
+    program: &str,
+    (out, rolls): (String, Vec<DiceRoll>),
+) -> String {
+    let out = format!(
+        "Secret roll of {} in <#{}>\n{}",
+        origin.tag, origin.channel, out
+    );
+    let mut users = gms(handler, ctx, origin).await.unwrap_or_else(|e| {
+        warn!(error = %e, "unable to find the GMs");
+        vec![]
+    });
+    users.retain(|u| *u != origin.user);
+    users.insert(0, origin.user);
+
+    let mut failed = vec![];
+    for user in users {
+        let sent = match user.create_dm_channel(&ctx.http).await {
+            Ok(dm) => {
+                let rolled = (out.clone(), rolls.clone());
+                handler
+                    .try_send_roll(ctx, (dm.id, origin.guild), program, rolled)
+                    .await
+            }
+            Err(why) => Err(why),
+        };
+        if let Err(why) = sent {
+            warn!(error = ?why, user = user.0, "unable to send secret roll");
+            // the others are not sent a roll the roller never saw
+            if user == origin.user {
+                return format!("<@{}>, I am unable to send you direct messages", user);
+            }
+            failed.push(user);
+        }
+    }
+    notice(origin.user, &failed)
+}
+
+/// Notice of a secret roll by `roller`, naming the GMs it could not be sent to.
+fn notice(roller: UserId, failed: &[UserId]) -> String {
+    let mut out = format!("<@{}> rolled in secret", roller);
+    if !failed.is_empty() {
+        let failed: Vec<_> = failed.iter().map(|u| format!("<@{}>", u)).collect();
+        out += &format!(", but I couldn't DM {}", failed.join(", "));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices() {
+        assert_eq!(notice(UserId(1), &[]), "<@1> rolled in secret");
+        assert_eq!(
+            notice(UserId(1), &[UserId(2), UserId(3)]),
+            "<@1> rolled in secret, but I couldn't DM <@2>, <@3>"
+        );
+    }
+}
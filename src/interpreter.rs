@@ -0,0 +1,194 @@
+use crate::error::Error;
+use crate::evaluator::{evaluate, DiceRoll, EvArray, Limits, State};
+use crate::parser::{lex, parse_program, Stmt, Token};
+use std::collections::HashMap;
+use tracing::trace;
+
+/// What running a program produced.
+#[derive(Clone, Debug)]
+pub struct Output {
+    /// Everything printed by `$` and `~` statements.
+    pub text: String,
+    /// Value output by the last `$` statement run, if any.
+    pub value: Option<EvArray>,
+}
+
+/// Runs programs one after the other, the variables and functions defined by one of them
+/// remaining available to the next ones.
+pub struct Interpreter {
+    state: State,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new(Limits::default())
+    }
+}
+
+impl Interpreter {
+    /// Creates an interpreter rolling dice from a random seed.
+    pub fn new(limits: Limits) -> Self {
+        Interpreter {
+            state: State::new(limits),
+        }
+    }
+
+    /// Creates an interpreter rolling dice from `seed`.
+    pub fn seeded(limits: Limits, seed: u64) -> Self {
+        Interpreter {
+            state: State::seeded(limits, seed),
+        }
+    }
+
+    /// Seed the dice were last (re)seeded from.
+    pub fn seed(&self) -> u64 {
+        self.state.seed()
+    }
+
+    /// Rolls the following dice from `seed`, keeping variables and functions.
+    pub fn reseed(&mut self, seed: u64) {
+        self.state.reseed(seed)
+    }
+
+    /// Variables left by the programs run so far.
+    pub fn vars(&self) -> &HashMap<String, EvArray> {
+        self.state.globals()
+    }
+
+    /// Defines a variable for the following programs, as if a program had assigned it.
+    pub fn set_var(&mut self, name: String, value: EvArray) {
+        self.state.set_global(name, value)
+    }
+
+    /// Takes the dice rolled since the last call, to show how results came about.
+    pub fn take_rolls(&mut self) -> Vec<DiceRoll> {
+        self.state.take_rolls()
+    }
+
+    /// Tokenizes, parses and runs `program`. Spans of the errors point into `program`.
+    pub fn eval(&mut self, program: &str) -> Result<Output, Error> {
+        let tokens = lex(program)?;
+        trace!(?tokens, "lexed");
+        let (parsed, spans) = parse_program(&tokens, program.len())?;
+        trace!(ast = ?parsed, "parsed");
+        let text = evaluate(&parsed, &mut self.state)
+            .map_err(|e| Error::Runtime(e, spans[self.state.statement()].clone()))?;
+        Ok(Output {
+            text,
+            value: self.state.take_last(),
+        })
+    }
+}
+
+/// Computes the probability of every result of `program`, a single output statement such
+/// as `$4d6h3s`, within `limits`. The `$` can be left out. Spans of the errors point into
+/// `program`.
+pub fn probabilities(program: &str, limits: Limits) -> Result<String, Error> {
+    analyse(program, |parsed| {
+        Ok(crate::distribution::render(
+            &crate::distribution::distribution(parsed, limits)?,
+        ))
+    })
+}
+
+/// Runs `program` `runs` times and computes statistics on its output, within `limits`. The
+/// `$` of a program outputting nothing, such as `4d6h3s`, can be left out. Spans of the
+/// errors point into `program`.
+pub fn statistics(program: &str, runs: usize, limits: Limits) -> Result<String, Error> {
+    analyse(program, |parsed| {
+        Ok(crate::stats::render(&crate::stats::sample(
+            parsed, runs, limits,
+        )?))
+    })
+}
+
+/// Parses `program`, outputting it if it outputs nothing, and runs `f` on it. Runtime errors
+/// point at the whole program.
+fn analyse(
+    program: &str,
+    f: impl FnOnce(&[Stmt]) -> Result<String, String>,
+) -> Result<String, Error> {
+    let mut tokens = lex(program)?;
+    let parsed = parse_program(&tokens, program.len());
+    // a program failing to parse is taken to be an expression unless it holds an output
+    let has_output = match &parsed {
+        Ok((stmts, _)) => outputs(stmts),
+        Err(_) => tokens
+            .iter()
+            .any(|(t, _)| matches!(t, Token::Output | Token::StringOutput)),
+    };
+    let parsed = if has_output {
+        parsed?.0
+    } else {
+        tokens.insert(0, (Token::Output, 0..0));
+        parse_program(&tokens, program.len())?.0
+    };
+    f(&parsed).map_err(|e| Error::Runtime(e, 0..program.len()))
+}
+
+/// Whether any of `stmts`, or of the statements of their blocks, outputs something.
+fn outputs(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|s| match s {
+        Stmt::Out(_) | Stmt::StringOut(_) => true,
+        Stmt::Condition(_, then, otherwise) => {
+            matches!(then, Some(b) if outputs(b)) || matches!(otherwise, Some(b) if outputs(b))
+        }
+        Stmt::While(_, body) | Stmt::For(_, _, body) | Stmt::Function(_, _, body) => {
+            matches!(body, Some(b) if outputs(b))
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_optional() {
+        let limits = Limits::default();
+        assert_eq!(
+            probabilities("1d4s", limits).unwrap(),
+            probabilities("$1d4s", limits).unwrap()
+        );
+        assert!(statistics("2d6s", 10, limits)
+            .unwrap()
+            .starts_with("10 runs"));
+        // outputs are found among the statements rather than in the text
+        assert_eq!(
+            probabilities("1d4+\"$\"", limits).unwrap(),
+            probabilities("$1d4+[36]", limits).unwrap()
+        );
+        assert_eq!(
+            probabilities("~1d4", limits).unwrap_err(),
+            Error::Runtime(
+                "Probabilities can only be computed for a single expression".to_owned(),
+                0..4
+            )
+        );
+    }
+
+    #[test]
+    fn errors_point_into_the_program() {
+        let limits = Limits::default();
+        assert_eq!(
+            probabilities("1d4s)", limits).unwrap_err(),
+            Error::Parse("Invalid Token ')'".to_owned(), 4..5)
+        );
+        assert_eq!(
+            probabilities("$1d4s)", limits).unwrap_err(),
+            Error::Parse("Invalid Token ')'".to_owned(), 5..6)
+        );
+        assert_eq!(
+            statistics("1#2", 10, limits).unwrap_err(),
+            Error::Lex("Unknown character '#'".to_owned(), 1..2)
+        );
+        assert_eq!(
+            probabilities("1d4s+A", limits).unwrap_err(),
+            Error::Runtime(
+                "Variables cannot be used when computing probabilities : 'A'".to_owned(),
+                0..6
+            )
+        );
+    }
+}
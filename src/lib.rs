@@ -0,0 +1,12 @@
+//! The dice language behind the Dicer bot. [`Interpreter`] runs programs while keeping
+//! their variables, functions and dice generator from one call to the next.
+
+pub mod distribution;
+pub mod error;
+pub mod evaluator;
+mod interpreter;
+pub mod parser;
+pub mod stats;
+
+pub use error::{Error, Span};
+pub use interpreter::{probabilities, statistics, Interpreter, Output};
@@ -0,0 +1,313 @@
+use crate::storage::{Scope, Storage};
+use crate::{Ran, Writes};
+use dicer_rs::parser::{is_name, lex, parse_program};
+use dicer_rs::Interpreter;
+
+/// Splits the parameters `(A, B)` heading a macro from its program.
+fn split_params(def: &str) -> Result<(Vec<String>, &str), String> {
+    let def = def.trim_start();
+    let (params, program) = match def.strip_prefix('(') {
+        Some(rest) => rest
+            .split_once(')')
+            .ok_or_else(|| "Unterminated parameter list".to_owned())?,
+        None => return Ok((vec![], def)),
+    };
+    let mut out: Vec<String> = vec![];
+    for p in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        if !is_name(p) {
+            return Err(format!("Invalid parameter '{}'", p));
+        }
+        if out.iter().any(|o| o == p) {
+            return Err(format!("Duplicate parameter '{}'", p));
+        }
+        out.push(p.to_owned());
+    }
+    Ok((out, program))
+}
+
+/// Splits the arguments of `!dice run` on the commas that are not nested in brackets.
+fn split_args(args: &str) -> Vec<String> {
+    let mut out = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in args.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                out.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    out.push(current);
+    out.into_iter()
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Scopes macros can be saved to, the innermost first.
+fn macro_scopes(scopes: &[Scope]) -> impl Iterator<Item = &Scope> {
+    scopes
+        .iter()
+        .rev()
+        .filter(|s| !matches!(s, Scope::Channel(_)))
+}
+
+/// Reads an optional `user` or `guild` heading `args`, the user being the default.
+fn target<'a>(scopes: &[Scope], args: &'a str) -> Result<(Scope, &'a str), String> {
+    let args = args.trim_start();
+    let (kind, rest) = match args.split_once(' ') {
+        Some((kind @ ("user" | "guild"), rest)) => (kind, rest),
+        _ => ("user", args),
+    };
+    let scope = macro_scopes(scopes)
+        .find(|s| s.kind() == kind)
+        .ok_or_else(|| format!("There is no {} here", kind))?;
+    Ok((*scope, rest.trim_start()))
+}
+
+/// Whether `args`, as given to [`save`] or [`forget`], are about the macros of the guild.
+pub fn for_guild(args: &str) -> bool {
+    args.split_whitespace().next() == Some("guild")
+}
+
+/// Saves a macro given as `[user|guild] NAME(PARAMS) program`, parameters being optional.
+pub fn save(storage: &dyn Storage, scopes: &[Scope], args: &str) -> Result<String, String> {
+    let (scope, def) = target(scopes, args)?;
+    let name_len = def
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .count();
+    let (name, def) = def.split_at(def.char_indices().nth(name_len).map_or(def.len(), |c| c.0));
+    if name.is_empty() {
+        return Err("Expected the name of the macro".to_owned());
+    }
+    if !is_name(name) {
+        return Err(format!("Invalid macro name '{}'", name));
+    }
+    let (_, program) = split_params(def)?;
+
+    // rejecting broken programs now spares a surprise when running them
+    lex(program)
+        .and_then(|t| parse_program(&t, program.len()))
+        .map_err(|e| format!("```\n{}\n```", e.render(program)))?;
+
+    storage.store_macro(scope, name, def.trim())?;
+    Ok(format!("Saved {} macro '{}'", scope.kind(), name))
+}
+
+/// Runs a macro given as `NAME arguments`, arguments being separated by commas. A macro
+/// of the user shadows the guild's one.
+pub fn run(
+    interpreter: &mut Interpreter,
+    storage: &dyn Storage,
+    scopes: &[Scope],
+    writes: Writes,
+    args: &str,
+) -> Result<Ran, String> {
+    let args = args.trim();
+    let (name, args) = args.split_once(' ').unwrap_or((args, ""));
+    let mut def = None;
+    for scope in macro_scopes(scopes) {
+        if let Some(d) = storage.load_macros(*scope)?.remove(name) {
+            def = Some(d);
+            break;
+        }
+    }
+    let def = def.ok_or_else(|| format!("Unknown macro '{}'", name))?;
+    let (params, program) = split_params(&def)?;
+
+    let args = split_args(args);
+    if args.len() != params.len() {
+        return Err(format!(
+            "Macro '{}' expects {} arguments but {} were given",
+            name,
+            params.len(),
+            args.len()
+        ));
+    }
+    let bindings = params.into_iter().zip(args).collect::<Vec<_>>();
+    crate::run_program(interpreter, storage, scopes, writes, program, &bindings)
+}
+
+/// Names of the macros available to the user, sorted.
+pub fn names(storage: &dyn Storage, scopes: &[Scope]) -> Result<Vec<String>, String> {
+    let mut out = vec![];
+    for scope in macro_scopes(scopes) {
+        out.extend(storage.load_macros(*scope)?.into_keys());
+    }
+    out.sort();
+    out.dedup();
+    Ok(out)
+}
+
+pub fn list(storage: &dyn Storage, scopes: &[Scope]) -> Result<String, String> {
+    let mut out = String::new();
+    for scope in macro_scopes(scopes) {
+        let macros = storage.load_macros(*scope)?;
+        let mut names = macros.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let def = &macros[name];
+            let sep = if def.starts_with('(') { "" } else { " " };
+            out = format!("{}{} : {}{}{}\n", out, scope.kind(), name, sep, def);
+        }
+    }
+    if out.is_empty() {
+        out = "No macro is saved".to_owned();
+    }
+    Ok(out)
+}
+
+/// Deletes a macro given as `[user|guild] NAME`.
+pub fn forget(storage: &dyn Storage, scopes: &[Scope], args: &str) -> Result<String, String> {
+    let (scope, name) = target(scopes, args)?;
+    let name = name.trim();
+    if storage.delete_macro(scope, name)? {
+        Ok(format!("Deleted {} macro '{}'", scope.kind(), name))
+    } else {
+        Err(format!("Unknown {} macro '{}'", scope.kind(), name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SqliteStorage;
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            split_params("(BONUS, max_hp) $1d20s+BONUS"),
+            Ok((
+                vec!["BONUS".to_owned(), "max_hp".to_owned()],
+                " $1d20s+BONUS"
+            ))
+        );
+        assert_eq!(split_params(" $8d6s"), Ok((vec![], "$8d6s")));
+        assert_eq!(
+            split_params("(Max) $Max"),
+            Err("Invalid parameter 'Max'".to_owned())
+        );
+        assert_eq!(
+            split_params("(A, A) $A"),
+            Err("Duplicate parameter 'A'".to_owned())
+        );
+    }
+
+    const SCOPES: [Scope; 3] = [Scope::Guild(1), Scope::Channel(2), Scope::User(3)];
+
+    fn run(storage: &dyn Storage, args: &str) -> Result<String, String> {
+        let writes = crate::Writes {
+            target: None,
+            guild: false,
+            limits: Default::default(),
+        };
+        super::run(&mut Interpreter::default(), storage, &SCOPES, writes, args).map(|r| r.out)
+    }
+
+    #[test]
+    fn scopes() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        assert_eq!(
+            save(&storage, &SCOPES, "guild FB $8"),
+            Ok("Saved guild macro 'FB'".to_owned())
+        );
+        assert_eq!(run(&storage, "FB"), Ok("8\n".to_owned()));
+        // the user's macro shadows the guild's one
+        assert_eq!(
+            save(&storage, &SCOPES, "FB $6"),
+            Ok("Saved user macro 'FB'".to_owned())
+        );
+        assert_eq!(run(&storage, "FB"), Ok("6\n".to_owned()));
+        assert_eq!(names(&storage, &SCOPES), Ok(vec!["FB".to_owned()]));
+        assert_eq!(
+            list(&storage, &SCOPES).unwrap(),
+            "user : FB $6\nguild : FB $8\n"
+        );
+
+        assert_eq!(
+            forget(&storage, &SCOPES, "FB"),
+            Ok("Deleted user macro 'FB'".to_owned())
+        );
+        assert_eq!(run(&storage, "FB"), Ok("8\n".to_owned()));
+        assert_eq!(
+            forget(&storage, &SCOPES, "FB"),
+            Err("Unknown user macro 'FB'".to_owned())
+        );
+        assert_eq!(
+            forget(&storage, &SCOPES, "guild FB"),
+            Ok("Deleted guild macro 'FB'".to_owned())
+        );
+        assert_eq!(run(&storage, "FB"), Err("Unknown macro 'FB'".to_owned()));
+
+        // direct messages have no guild
+        assert_eq!(
+            save(&storage, &SCOPES[1..], "guild FB $8"),
+            Err("There is no guild here".to_owned())
+        );
+    }
+
+    #[test]
+    fn arguments() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save(&storage, &SCOPES, "ATK(BONUS, DICE) $BONUS+DICE").unwrap();
+        assert_eq!(run(&storage, "ATK 5, [1,2]"), Ok("[6,7]\n".to_owned()));
+        assert_eq!(
+            run(&storage, "ATK 5"),
+            Err("Macro 'ATK' expects 2 arguments but 1 were given".to_owned())
+        );
+        // parameters are not stored as variables
+        assert!(storage.load(SCOPES[2]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replays() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save(&storage, &SCOPES, "ATK(BONUS, DICE) $1d20s+BONUS+DICEs").unwrap();
+        let writes = crate::Writes {
+            target: None,
+            guild: false,
+            limits: Default::default(),
+        };
+        let mut interpreter = Interpreter::seeded(Default::default(), 7);
+        let ran = super::run(&mut interpreter, &storage, &SCOPES, writes, "ATK 5, 4d4").unwrap();
+        assert_eq!(ran.program, "BONUS=(5)\nDICE=(4d4)\n $1d20s+BONUS+DICEs");
+        // the evaluated program rolls the same dice from the same seed
+        let replayed = Interpreter::seeded(Default::default(), 7)
+            .eval(&ran.program)
+            .unwrap();
+        assert_eq!(replayed.text, ran.out);
+    }
+
+    #[test]
+    fn invalid_macros() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        assert_eq!(
+            save(&storage, &SCOPES, "Max $1"),
+            Err("Invalid macro name 'Max'".to_owned())
+        );
+        assert_eq!(
+            save(&storage, &SCOPES, "$1"),
+            Err("Expected the name of the macro".to_owned())
+        );
+        assert!(save(&storage, &SCOPES, "FB $1)")
+            .unwrap_err()
+            .starts_with("```"));
+        assert!(storage.load_macros(SCOPES[2]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn guild_macros_are_told_apart() {
+        // those are the ones whose changes need permission
+        assert!(for_guild("guild FB $8d6s"));
+        assert!(for_guild("  guild FB"));
+        assert!(!for_guild("FB $8d6s"));
+        assert!(!for_guild("user guild"));
+        assert!(!for_guild("guildFB $1"));
+    }
+}
@@ -1,183 +1,838 @@
 use serenity::async_trait;
-use serenity::client::{Client, Context, EventHandler};
+use serenity::client::{Context, EventHandler};
+use serenity::model::application::interaction::Interaction;
 use serenity::model::channel::Message;
+use serenity::model::gateway::Ready;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::user::User;
+use serenity::model::Permissions;
+use std::sync::Arc;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-mod evaluator;
-mod parser;
+use dicer_rs::evaluator::{DiceRoll, EvArray, Limits};
+use dicer_rs::{probabilities, statistics, Interpreter};
 
-struct Handler;
+mod clear;
+mod cli;
+mod config;
+mod embed;
+mod gm;
+mod macros;
+mod repl;
+mod reply;
+mod slash;
+mod storage;
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn message(&self, ctx: Context, mut msg: Message) {
-        let inter = if msg.content.ends_with("is this true?") {
-            "!dice $1d6".to_owned()
-        } else {
-            msg.content.clone()
-        };
-
-        let mut m = inter.clone();
-        println!("{m}");
-        m.truncate(6);
+use storage::{Scope, Storage, VariableLimits};
 
-        if inter == "!dice help" || msg.content == "!dicer help" {
-            if let Err(why) = msg.channel_id.say(&ctx.http, "Dicer is a dice roller bot designed for tabletop rpg. It is based on an innovative representation of rolls as mathematical expressions, allowing endless possibilities, end thus making it suitable no matter the rules you are using.
-!dice followed by a command outputs the result of this command.
+const HELP: &str = "Dicer is a dice roller bot designed for tabletop rpg. It is based on an innovative representation of rolls as mathematical expressions, allowing endless possibilities, end thus making it suitable no matter the rules you are using.
+!dice followed by a command outputs the result of this command, as does /roll.
 there also some specific commands :
 !dice help		: displays this help
-!dice clear		: clears the channel").await {
-                println!("error sending message : {:?}", why);
+!dice prob		: shows the probability of every result of an expression, e.g. !dice prob 4d6h3s
+!dice stats		: runs a program many times and shows statistics on its output, e.g. !dice stats 1000 $4d6h3s
+!dice vars		: lists your variables and the ones of this channel and server
+!dice var		: shows where a variable is stored, e.g. !dice var HP
+!dice unset		: deletes one of your variables, or of this channel or server, e.g. !dice unset channel HP
+!dice channel	: runs a program, storing the variables it sets for the whole channel, e.g. !dice channel HP=30
+!dice guild		: runs a program, storing the variables it sets for the whole server
+!dice save		: saves a program as a macro, for you or the whole server, e.g. !dice save guild ATK(BONUS) $1d20s+BONUS
+!dice run		: runs a macro, e.g. !dice run ATK 5
+!dice macros	: lists your macros and the ones of this server
+!dice forget	: deletes a macro, e.g. !dice forget guild ATK
+!dice gm		: runs a program in secret, sending its output to you and the GMs by direct message, e.g. !dice gm $1d20s
+!dice gmrole	: sets the role of the GMs of this server, or removes it with off, e.g. !dice gmrole @GM
+!dice clear		: deletes my messages among the last 100 of the channel, or every one with all, after confirmation, e.g. !dice clear all 50
+The slash commands /roll (secret option included), /stats and /macro do the same as their !dice counterparts, /help displays this help.";
+
+struct Handler {
+    storage: Arc<dyn Storage>,
+    config: config::Config,
+    pending_clears: clear::Pending,
+}
+
+/// Who sent a command and where, be it a message or a slash command.
+#[derive(Clone)]
+struct Origin {
+    user: UserId,
+    tag: String,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+}
+
+impl Origin {
+    fn new(user: &User, channel: ChannelId, guild: Option<GuildId>) -> Self {
+        Origin {
+            user: user.id,
+            tag: user.tag(),
+            channel,
+            guild,
+        }
+    }
+
+    /// Scopes whose variables are visible to the user, the outermost first.
+    fn scopes(&self) -> Vec<Scope> {
+        let mut out = vec![];
+        if let Some(guild) = self.guild {
+            out.push(Scope::Guild(guild.0));
+        }
+        out.push(Scope::Channel(self.channel.0));
+        out.push(Scope::User(self.user.0));
+        out
+    }
+}
+
+/// Output of a program run by [`run_program`].
+struct Ran {
+    out: String,
+    /// Program evaluated, macros being expanded along with their arguments, so that running
+    /// it again from the same seed rolls the same dice.
+    program: String,
+}
+
+/// Where the variables changed by a program may be stored.
+#[derive(Clone, Copy)]
+struct Writes {
+    /// Scope storing every variable changed, instead of the one each of them came from.
+    target: Option<Scope>,
+    /// Whether the variables of the guild may be changed.
+    guild: bool,
+    limits: VariableLimits,
+}
+
+impl Handler {
+    /// Runs a program for `origin` with a fresh interpreter on a blocking thread, logging its
+    /// seed and recording what `run` evaluated in the roll history. `program` is what the user
+    /// asked for, recorded if `run` fails. Returns the output along with the dice rolled, none
+    /// being returned on error.
+    async fn roll(
+        &self,
+        origin: &Origin,
+        program: &str,
+        run: impl FnOnce(&mut Interpreter, &dyn Storage) -> Result<Ran, String> + Send + 'static,
+    ) -> (String, Vec<DiceRoll>) {
+        let storage = Arc::clone(&self.storage);
+        let limits = self.config.limits();
+        let (origin, program) = (origin.clone(), program.to_owned());
+        unblock(move || Handler::roll_blocking(&*storage, limits, &origin, &program, run))
+            .await
+            .unwrap_or_else(|e| (e, vec![]))
+    }
+
+    /// The work of [`Handler::roll`], which blocks.
+    fn roll_blocking(
+        storage: &dyn Storage,
+        limits: Limits,
+        origin: &Origin,
+        program: &str,
+        run: impl FnOnce(&mut Interpreter, &dyn Storage) -> Result<Ran, String>,
+    ) -> (String, Vec<DiceRoll>) {
+        let mut interpreter = Interpreter::new(limits);
+        // programs can hold whatever users type, so that they are kept out of the usual logs
+        debug!(program, "rolling");
+
+        let (out, rolls, program) = match run(&mut interpreter, storage) {
+            Ok(ran) => (ran.out, interpreter.take_rolls(), ran.program),
+            Err(e) => (e, vec![], program.to_owned()),
+        };
+        debug!(output = %out, "rolled");
+        let roll = storage::Roll {
+            time: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            user: origin.user.0,
+            channel: origin.channel.0,
+            guild: origin.guild.map(|g| g.0),
+            program: &program,
+            seed: interpreter.seed(),
+        };
+        let id = storage.record_roll(&roll).unwrap_or_else(|e| {
+            error!(error = %e, "unable to record roll");
+            None
+        });
+        info!(user = %origin.tag, seed = roll.seed, roll = id, "roll");
+        (out, rolls)
+    }
+
+    /// Checks that `origin` may save or forget the macros `args` are about, see
+    /// [`gm::check_manager`].
+    async fn check_macros(&self, ctx: &Context, origin: &Origin, args: &str) -> Result<(), String> {
+        if macros::for_guild(args) {
+            gm::check_manager(self, ctx, origin, "macros").await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Where a program run for `origin` may store the variables it changes, every one of
+    /// them going to `target` if given. Changing the variables of the guild takes the same
+    /// permissions as its macros, which are only checked when it has some or is the target.
+    async fn writes(
+        &self,
+        ctx: &Context,
+        origin: &Origin,
+        target: Option<Scope>,
+    ) -> Result<Writes, String> {
+        let guild = match (origin.guild, target) {
+            (None, _) => false,
+            (Some(_), Some(Scope::Guild(_))) => {
+                gm::check_manager(self, ctx, origin, "variables").await?;
+                true
             }
-        } else if m == "!dice " {
-            let mut content = inter[6..].to_owned();
-            if !content.ends_with('\n') {
-                content.push('\n');
+            (Some(guild), _) => {
+                !self.storage.load(Scope::Guild(guild.0))?.is_empty()
+                    && gm::check_manager(self, ctx, origin, "variables")
+                        .await
+                        .is_ok()
             }
-            println!("{:?}", content);
-            let to_unwrap = crate::parser::tokenize(content.clone());
+        };
+        Ok(Writes {
+            target,
+            guild,
+            limits: self.config.variables,
+        })
+    }
 
-            if let Err(e) = to_unwrap.clone() {
-                if let Err(why) = msg.channel_id.say(&ctx.http, e).await {
-                    eprintln!("error sending message : {:?}", why);
-                    return;
-                }
-                if let Err(why) = msg
-                    .channel_id
-                    .say(&ctx.http, format!("received: {content}"))
-                    .await
-                {
-                    eprintln!("error sending message : {:?}", why);
-                    return;
+    /// Sends the output of `program` to `channel`, in an embed showing the dice it rolled
+    /// if there are any and embeds are enabled in `guild`.
+    async fn send_roll(
+        &self,
+        ctx: &Context,
+        to: (ChannelId, Option<GuildId>),
+        program: &str,
+        rolled: (String, Vec<DiceRoll>),
+    ) {
+        if let Err(why) = self.try_send_roll(ctx, to, program, rolled).await {
+            warn!(error = ?why, "unable to send message");
+        }
+    }
+
+    /// Sends the output of a roll like [`Handler::send_roll`], returning why it failed.
+    async fn try_send_roll(
+        &self,
+        ctx: &Context,
+        (channel, guild): (ChannelId, Option<GuildId>),
+        program: &str,
+        (out, rolls): (String, Vec<DiceRoll>),
+    ) -> serenity::Result<()> {
+        if out.is_empty() {
+            return Ok(());
+        }
+        if !self.config.features(guild).embeds || rolls.is_empty() || !embed::fits(&out) {
+            return self.try_send(ctx, channel, &out).await;
+        }
+        channel
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| embed::roll(e, program, &out, &rolls))
+            })
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends `text` to `channel`, split over several messages or as a file if it is too long.
+    async fn send(&self, ctx: &Context, channel: ChannelId, text: &str) {
+        if let Err(why) = self.try_send(ctx, channel, text).await {
+            warn!(error = ?why, "unable to send message");
+        }
+    }
+
+    async fn try_send(
+        &self,
+        ctx: &Context,
+        channel: ChannelId,
+        text: &str,
+    ) -> serenity::Result<()> {
+        match reply::prepare(text, &self.config.output) {
+            reply::Reply::Messages(messages) => {
+                for m in messages {
+                    channel.say(&ctx.http, m).await?;
                 }
+                Ok(())
             }
-            let tokens = to_unwrap.unwrap();
-            println!("{:?}", tokens);
-
-            let mut i = 0;
-            let to_unwrap = crate::parser::parse(&tokens, &mut i);
-            if let Err(e) = to_unwrap.clone() {
-                if let Err(why) = msg.channel_id.say(&ctx.http, e).await {
-                    eprintln!("error sending message : {:?}", why);
-                    return;
-                }
-                if let Err(why) = msg
-                    .channel_id
-                    .say(&ctx.http, format!("received: {content}"))
-                    .await
-                {
-                    eprintln!("error sending message : {:?}", why);
-                    return;
-                }
-                if let Err(why) = msg
-                    .channel_id
-                    .say(&ctx.http, format!("tokenized into: {tokens:?}"))
-                    .await
-                {
-                    eprintln!("error sending message : {:?}", why);
-                    return;
-                }
+            reply::Reply::File(note, content) => channel
+                .send_message(&ctx.http, |m| {
+                    m.content(note).add_file(reply::attachment(content))
+                })
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn message(&self, ctx: Context, msg: Message) {
+        // messages and interactions are told apart in logs by their id
+        let span = info_span!("message", id = msg.id.0, user = msg.author.id.0);
+        self.on_message(ctx, msg).instrument(span).await
+    }
+
+    async fn ready(&self, ctx: Context, _: Ready) {
+        if !self.config.features(None).slash_commands {
+            return;
+        }
+        if let Err(why) = slash::register(&ctx).await {
+            error!(error = ?why, "unable to register slash commands");
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::ApplicationCommand(command) => {
+                let span = info_span!("command", id = command.id.0, user = command.user.id.0);
+                slash::run(self, &ctx, &command).instrument(span).await
             }
-            let parsed = to_unwrap.unwrap();
-            println!("{:?}", parsed);
-
-            let to_unwrap =
-                crate::evaluator::evaluate(&parsed, &mut std::collections::HashMap::new());
-            if let Err(e) = to_unwrap.clone() {
-                if e != "" {
-                    if let Err(why) = msg.channel_id.say(&ctx.http, e).await {
-                        eprintln!("error sending message : {:?}", why);
-                        return;
-                    }
-                    if let Err(why) = msg
-                        .channel_id
-                        .say(&ctx.http, format!("received: {content}"))
-                        .await
-                    {
-                        eprintln!("error sending message : {:?}", why);
-                        return;
-                    }
-                    if let Err(why) = msg
-                        .channel_id
-                        .say(&ctx.http, format!("tokenized into: {tokens:?}"))
-                        .await
-                    {
-                        eprintln!("error sending message : {:?}", why);
-                        return;
-                    }
-                    if let Err(why) = msg
-                        .channel_id
-                        .say(&ctx.http, format!("parsed as: {parsed:?}"))
-                        .await
-                    {
-                        eprintln!("error sending message : {:?}", why);
-                        return;
+            Interaction::Autocomplete(a) => slash::autocomplete(self, &ctx, &a).await,
+            _ => {}
+        }
+    }
+}
+
+impl Handler {
+    /// Answers the commands sent as messages.
+    async fn on_message(&self, ctx: Context, msg: Message) {
+        let features = self.config.features(msg.guild_id);
+        if !features.prefix_commands {
+            return;
+        }
+        let content = msg.content.trim();
+        let cmd = if features.easter_egg && content.ends_with("is this true?") {
+            "$1d6"
+        } else if content == "!dicer help" {
+            "help"
+        } else {
+            let prefixes = self.config.prefixes(msg.guild_id);
+            match prefixes.iter().find_map(|p| strip_command(content, p)) {
+                Some(cmd) if !cmd.is_empty() => cmd,
+                _ => return,
+            }
+        };
+        let origin = Origin::new(&msg.author, msg.channel_id, msg.guild_id);
+        let place = (msg.channel_id, msg.guild_id);
+        if cmd == "help" {
+            let out = self.config.help(msg.guild_id);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(expr) = cmd.strip_prefix("prob ") {
+            let (expr, limits) = (expr.to_owned(), self.config.limits());
+            let out = unblock(move || prob_command(&expr, limits))
+                .await
+                .unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(args) = cmd.strip_prefix("stats ") {
+            let (args, limits) = (args.to_owned(), self.config.limits());
+            let out = unblock(move || stats_command(&args, limits))
+                .await
+                .unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if cmd == "vars" {
+            let out = list_vars(&*self.storage, &origin.scopes()).unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(name) = cmd.strip_prefix("var ") {
+            let out = show_var(&*self.storage, &origin.scopes(), name.trim()).unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(args) = cmd.strip_prefix("unset ") {
+            let out = if args.split_whitespace().next() == Some("guild") {
+                gm::check_manager(self, &ctx, &origin, "variables").await
+            } else {
+                Ok(())
+            };
+            let out = out
+                .and_then(|_| unset_var(&*self.storage, &origin.scopes(), args))
+                .unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(program) = cmd.strip_prefix("gm ") {
+            if !features.secret_rolls {
+                let out = "Secret rolls are disabled here";
+                return self.send(&ctx, msg.channel_id, out).await;
+            }
+            let writes = match self.writes(&ctx, &origin, None).await {
+                Ok(w) => w,
+                Err(e) => return self.send(&ctx, msg.channel_id, &e).await,
+            };
+            let (scopes, owned) = (origin.scopes(), program.to_owned());
+            let rolled = self
+                .roll(&origin, program, move |interpreter, storage| {
+                    run_program(interpreter, storage, &scopes, writes, &owned, &[])
+                })
+                .await;
+            let notice = gm::send(self, &ctx, &origin, program, rolled).await;
+            self.send(&ctx, msg.channel_id, &notice).await;
+        } else if let Some(args) = cmd.strip_prefix("gmrole ") {
+            let out = gm::set_role(self, &ctx, &origin, args).await;
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if cmd == "clear" || cmd.starts_with("clear ") {
+            let out = if features.clear {
+                clear::clear(self, &ctx, &origin, msg.id, &cmd[5..]).await
+            } else {
+                "Clearing is disabled here".to_owned()
+            };
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(args) = cmd.strip_prefix("save ") {
+            let out = match self.check_macros(&ctx, &origin, args).await {
+                Ok(()) => macros::save(&*self.storage, &origin.scopes(), args),
+                Err(e) => Err(e),
+            };
+            let out = out.unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(args) = cmd.strip_prefix("run ") {
+            let writes = match self.writes(&ctx, &origin, None).await {
+                Ok(w) => w,
+                Err(e) => return self.send(&ctx, msg.channel_id, &e).await,
+            };
+            let (scopes, args) = (origin.scopes(), args.to_owned());
+            let rolled = self
+                .roll(&origin, cmd, move |interpreter, storage| {
+                    macros::run(interpreter, storage, &scopes, writes, &args)
+                })
+                .await;
+            self.send_roll(&ctx, place, cmd, rolled).await;
+        } else if cmd == "macros" {
+            let out = macros::list(&*self.storage, &origin.scopes()).unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else if let Some(args) = cmd.strip_prefix("forget ") {
+            let out = match self.check_macros(&ctx, &origin, args).await {
+                Ok(()) => macros::forget(&*self.storage, &origin.scopes(), args),
+                Err(e) => Err(e),
+            };
+            let out = out.unwrap_or_else(|e| e);
+            self.send(&ctx, msg.channel_id, &out).await;
+        } else {
+            let scopes = origin.scopes();
+            let (target, content) = match cmd.split_once(' ') {
+                Some((kind @ ("user" | "channel" | "guild"), program)) => {
+                    match scopes.iter().find(|s| s.kind() == kind) {
+                        Some(scope) => (Some(*scope), program),
+                        None => {
+                            let out = format!("There is no {} here", kind);
+                            self.send(&ctx, msg.channel_id, &out).await;
+                            return;
+                        }
                     }
                 }
-            }
-            let evaluated = to_unwrap.unwrap();
-            println!("{}", evaluated);
+                _ => (None, cmd),
+            };
+            let writes = match self.writes(&ctx, &origin, target).await {
+                Ok(w) => w,
+                Err(e) => return self.send(&ctx, msg.channel_id, &e).await,
+            };
+            let program = content.to_owned();
+            let rolled = self
+                .roll(&origin, content, move |interpreter, storage| {
+                    run_program(interpreter, storage, &scopes, writes, &program, &[])
+                })
+                .await;
+            self.send_roll(&ctx, place, content, rolled).await;
+        }
+    }
+}
 
-            if evaluated != "" {
-                if let Err(why) = msg.channel_id.say(&ctx.http, evaluated).await {
-                    eprintln!("error sending message : {:?}", why);
-                }
-            }
+/// Runs `f`, which may take a while, on a thread set aside for blocking work, so that the
+/// other events are not held up meanwhile.
+async fn unblock<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, String> {
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .map_err(|e| {
+            error!(error = %e, "blocking task failed");
+            "Something went wrong".to_owned()
+        })
+}
+
+/// What follows `prefix` in a message, if it starts with it as a word of its own.
+fn strip_command<'a>(content: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = content.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// Whether `user` has `permission` in `guild`, through its roles or by owning it.
+async fn has_permission(
+    ctx: &Context,
+    guild: GuildId,
+    user: UserId,
+    permission: Permissions,
+) -> serenity::Result<bool> {
+    let guild = guild.to_partial_guild(&ctx.http).await?;
+    if guild.owner_id == user {
+        return Ok(true);
+    }
+    let member = guild.id.member(&ctx.http, user).await?;
+    // the @everyone role shares its id with the guild
+    let permissions = guild
+        .roles
+        .values()
+        .filter(|r| r.id.0 == guild.id.0 || member.roles.contains(&r.id))
+        .fold(Permissions::empty(), |p, r| p | r.permissions);
+    Ok(permissions.contains(Permissions::ADMINISTRATOR) || permissions.contains(permission))
+}
+
+/// Runs `program` with the variables of `scopes` defined, an inner scope shadowing the outer
+/// ones. The variables it changes are then stored back into the scope they came from, new
+/// ones going to the user, unless `writes` has a target scope to store all of them. Nothing
+/// is stored if a single variable goes against `writes`.
+///
+/// `bindings` are variables assigned the value of an expression beforehand, which are not
+/// stored.
+fn run_program(
+    interpreter: &mut Interpreter,
+    storage: &dyn Storage,
+    scopes: &[Scope],
+    writes: Writes,
+    program: &str,
+    bindings: &[(String, String)],
+) -> Result<Ran, String> {
+    let mut origin = std::collections::HashMap::new();
+    for scope in scopes {
+        for (name, value) in storage.load(*scope)? {
+            origin.insert(name.clone(), *scope);
+            interpreter.set_var(name, value);
+        }
+    }
+    // the bindings roll their dice before the program, as if they were written ahead of it
+    let mut evaluated = String::new();
+    for (name, expr) in bindings {
+        let binding = format!("{}=({})", name, expr);
+        interpreter
+            .eval(&binding)
+            .map_err(|e| format!("```\n{}\n```", e.render(&binding)))?;
+        evaluated = format!("{}{}\n", evaluated, binding);
+    }
+    evaluated.push_str(program);
+    let before = interpreter.vars().clone();
+
+    let out = interpreter
+        .eval(program)
+        .map_err(|e| format!("```\n{}\n```", e.render(program)))?;
+
+    let user = scopes[scopes.len() - 1];
+    let mut changed = vec![];
+    for (name, value) in interpreter.vars() {
+        if before.get(name) != Some(value) && !bindings.iter().any(|(n, _)| n == name) {
+            let scope = writes
+                .target
+                .or_else(|| origin.get(name).copied())
+                .unwrap_or(user);
+            changed.push((scope, name, value));
         }
     }
+    check_writes(storage, &changed, writes)?;
+    for (scope, name, value) in changed {
+        storage.store(scope, name, value)?;
+    }
+    Ok(Ran {
+        out: out.text,
+        program: evaluated,
+    })
+}
+
+/// Checks that the variables `changed`, along with the scope each of them goes to, can be
+/// stored according to `writes`.
+fn check_writes(
+    storage: &dyn Storage,
+    changed: &[(Scope, &String, &EvArray)],
+    writes: Writes,
+) -> Result<(), String> {
+    let limits = writes.limits;
+    let mut scopes = vec![];
+    for (scope, _, _) in changed {
+        if !scopes.contains(scope) {
+            scopes.push(*scope);
+        }
+    }
+    for scope in scopes {
+        if matches!(scope, Scope::Guild(_)) && !writes.guild {
+            return Err(
+                "Changing the variables of the server takes the Manage Server permission or the GM role"
+                    .to_owned(),
+            );
+        }
+        let stored = storage.load(scope)?;
+        let added = changed
+            .iter()
+            .filter(|(s, name, _)| *s == scope && !stored.contains_key(*name))
+            .count();
+        if stored.len() + added > limits.max_count {
+            return Err(format!(
+                "Cannot store more than {} {} variables",
+                limits.max_count,
+                scope.kind()
+            ));
+        }
+    }
+    if let Some((_, name, _)) = changed
+        .iter()
+        .find(|(_, _, v)| v.to_string().chars().count() > limits.max_len)
+    {
+        return Err(format!(
+            "Cannot store '{}', which is longer than {} characters",
+            name, limits.max_len
+        ));
+    }
+    Ok(())
+}
+
+fn list_vars(storage: &dyn Storage, scopes: &[Scope]) -> Result<String, String> {
+    let mut out = String::new();
+    for scope in scopes.iter().rev() {
+        let vars = storage.load(*scope)?;
+        let mut names = vars.keys().collect::<Vec<_>>();
+        names.sort();
+        let vars = names
+            .into_iter()
+            .map(|n| format!("{} = {}", n, vars[n]))
+            .collect::<Vec<_>>();
+        if !vars.is_empty() {
+            out = format!("{}{} : {}\n", out, scope.kind(), vars.join(", "));
+        }
+    }
+    if out.is_empty() {
+        out = "No variable is stored".to_owned();
+    }
+    Ok(out)
+}
+
+fn show_var(storage: &dyn Storage, scopes: &[Scope], name: &str) -> Result<String, String> {
+    let mut out = String::new();
+    // innermost first, as that is the one programs see
+    for scope in scopes.iter().rev() {
+        if let Some(value) = storage.load(*scope)?.get(name) {
+            out = format!("{}{} {} = {}\n", out, scope.kind(), name, value);
+        }
+    }
+    if out.is_empty() {
+        out = format!("Unknown variable '{}'", name);
+    }
+    Ok(out)
+}
+
+/// Deletes a variable given as `NAME` or `SCOPE NAME`, the user scope being the default.
+fn unset_var(storage: &dyn Storage, scopes: &[Scope], args: &str) -> Result<String, String> {
+    let (kind, name) = args.trim().split_once(' ').unwrap_or(("user", args.trim()));
+    let scope = scopes
+        .iter()
+        .find(|s| s.kind() == kind)
+        .ok_or_else(|| format!("There is no {} here", kind))?;
+    if storage.delete(*scope, name.trim())? {
+        Ok(format!("Deleted {} variable '{}'", kind, name.trim()))
+    } else {
+        Err(format!("Unknown {} variable '{}'", kind, name.trim()))
+    }
+}
+
+/// Answers `!dice prob` for an expression such as `4d6h3s`.
+fn prob_command(expr: &str, limits: Limits) -> String {
+    let expr = expr.trim();
+    probabilities(expr, limits).unwrap_or_else(|e| format!("```\n{}\n```", e.render(expr)))
+}
+
+/// Reads the arguments of `!dice stats`, an optional number of runs followed by the program,
+/// e.g. `1000 $4d6h3s`.
+fn stats_args(args: &str) -> (usize, &str) {
+    let args = args.trim_start();
+    let (runs, program) = match args.split_once(char::is_whitespace) {
+        Some((n, rest)) if n.parse::<usize>().is_ok() => (n.parse().unwrap(), rest),
+        _ => (10_000, args),
+    };
+    (runs, program.trim())
+}
+
+/// Answers `!dice stats`, see [`stats_args`].
+fn stats_command(args: &str, limits: Limits) -> String {
+    let (runs, program) = stats_args(args);
+    statistics(program, runs, limits).unwrap_or_else(|e| format!("```\n{}\n```", e.render(program)))
 }
 
-#[cfg(debug_assertions)]
 fn main() {
-    let mut buffer = String::new();
-    let stdin = std::io::stdin();
-    loop {
-        stdin.read_line(&mut buffer).unwrap();
-        let to_unwrap = crate::parser::tokenize(buffer.to_owned());
+    std::process::exit(cli::main(std::env::args().skip(1).collect()));
+}
 
-        println!("{}", buffer);
+/// Logs to the standard error what DICER_LOG lets through, or else `filter`, both being
+/// written as `EnvFilter` directives such as `info,dicer_rs::evaluator=trace`. Programs are
+/// only traced, tokens, syntax tree and statements included, at the `trace` level.
+fn init_logging(filter: &str) {
+    let filter = std::env::var("DICER_LOG").unwrap_or_else(|_| filter.to_owned());
+    let filter = tracing_subscriber::EnvFilter::try_new(&filter).unwrap_or_else(|e| {
+        eprintln!("invalid log filter '{}' : {}", filter, e);
+        tracing_subscriber::EnvFilter::new("info")
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .init();
+}
 
-        if let Err(e) = to_unwrap.clone() {
-            eprintln!("{}", e);
-            continue;
+/// Runs the bot until it stops, returning the exit code of the process.
+fn bot(config: Option<&std::path::Path>) -> i32 {
+    // deeply recursive programs need more than the default 2MiB of stack
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(16 * 1024 * 1024)
+        .build();
+    match runtime {
+        Ok(runtime) => runtime.block_on(run_bot(config)),
+        Err(e) => {
+            // logging is only set up once the config is read, by the runtime
+            eprintln!("unable to create runtime : {}", e);
+            cli::EXIT_FAILURE
         }
-        let tokens = to_unwrap.unwrap();
-        println!("{:?}", tokens);
+    }
+}
 
-        let mut i = 0;
-        let to_unwrap = crate::parser::parse(&tokens, &mut i);
-        if let Err(e) = to_unwrap.clone() {
+/// Opens the SQLite database of the config, or keeps variables and macros as plain files
+/// if it names a directory for them instead.
+fn open_storage(config: &config::Config) -> Result<Arc<dyn Storage>, String> {
+    Ok(match &config.variables_dir {
+        Some(dir) => Arc::new(storage::FileStorage::new(dir)?),
+        None => Arc::new(storage::SqliteStorage::open(&config.database)?),
+    })
+}
+
+/// Runs the bot with the config at `config`, or else the one named by DICER_CONFIG.
+async fn run_bot(config: Option<&std::path::Path>) -> i32 {
+    let path = config
+        .map(Into::into)
+        .or_else(|| std::env::var_os("DICER_CONFIG").map(std::path::PathBuf::from));
+    let started = config::Config::load(path.as_deref()).and_then(|config| {
+        let token = config.token()?;
+        let storage = open_storage(&config)?;
+        Ok((config, token, storage))
+    });
+    let (config, token, storage) = match started {
+        Ok(s) => s,
+        Err(e) => {
             eprintln!("{}", e);
-            continue;
+            return cli::EXIT_FAILURE;
         }
-        let parsed = to_unwrap.unwrap();
-        println!("{:?}", parsed);
+    };
+    init_logging(config.log.as_deref().unwrap_or("info"));
 
-        let to_unwrap = crate::evaluator::evaluate(&parsed, &mut std::collections::HashMap::new());
-        if let Err(e) = to_unwrap.clone() {
-            eprintln!("{}", e);
-            continue;
+    use serenity::client::Client;
+    use serenity::model::gateway::GatewayIntents;
+    // reading messages is a privileged intent, which slash commands do without
+    let mut intents = GatewayIntents::non_privileged();
+    if config.features(None).prefix_commands {
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+
+    let client = Client::builder(&token, intents)
+        .event_handler(Handler {
+            storage,
+            config,
+            pending_clears: Default::default(),
+        })
+        .await;
+    let mut client = match client {
+        Ok(c) => c,
+        Err(why) => {
+            error!(error = ?why, "unable to create client");
+            return cli::EXIT_FAILURE;
         }
-        let evaluated = to_unwrap.unwrap();
+    };
 
-        println!("{}", evaluated);
+    if let Err(why) = client.start().await {
+        error!(error = ?why, "client stopped");
+        return cli::EXIT_FAILURE;
     }
+    0
 }
 
-#[cfg(not(debug_assertions))]
-#[tokio::main]
-async fn main() {
-    let token = std::fs::read_to_string("token.txt")
-        .expect("unable to find token, please check token.txt location");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::SqliteStorage;
 
-    use serenity::model::gateway::GatewayIntents;
-    let intents = GatewayIntents::all();
+    const SCOPES: [Scope; 3] = [Scope::Guild(1), Scope::Channel(2), Scope::User(3)];
 
-    let mut client = Client::builder(&token, intents)
-        .event_handler(Handler)
-        .await
-        .expect("error creating client");
+    fn writes(target: Option<Scope>, guild: bool) -> Writes {
+        Writes {
+            target,
+            guild,
+            limits: VariableLimits::default(),
+        }
+    }
 
-    if let Err(why) = client.start().await {
-        println!("client error : {:?}", why);
+    fn run(storage: &dyn Storage, writes: Writes, program: &str) -> Result<String, String> {
+        let mut interpreter = Interpreter::default();
+        run_program(&mut interpreter, storage, &SCOPES, writes, program, &[]).map(|r| r.out)
+    }
+
+    fn var(storage: &dyn Storage, scope: Scope, name: &str) -> Option<EvArray> {
+        storage.load(scope).unwrap().remove(name)
+    }
+
+    #[test]
+    fn variables_stay_where_they_are_stored() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        run(&storage, writes(Some(SCOPES[1]), false), "HP=30").unwrap();
+        assert_eq!(var(&storage, SCOPES[1], "HP"), Some(EvArray::F(30.)));
+
+        assert_eq!(
+            run(&storage, writes(None, false), "HP=HP-4 $HP").unwrap(),
+            "26\n"
+        );
+        assert_eq!(var(&storage, SCOPES[1], "HP"), Some(EvArray::F(26.)));
+        assert_eq!(var(&storage, SCOPES[2], "HP"), None);
+
+        run(&storage, writes(None, false), "MP=5").unwrap();
+        assert_eq!(var(&storage, SCOPES[2], "MP"), Some(EvArray::F(5.)));
+
+        run(&storage, writes(Some(SCOPES[0]), true), "AC=12").unwrap();
+        assert_eq!(var(&storage, SCOPES[0], "AC"), Some(EvArray::F(12.)));
+    }
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        storage.store(SCOPES[0], "X", &EvArray::F(1.)).unwrap();
+        assert_eq!(run(&storage, writes(None, false), "$X").unwrap(), "1\n");
+        storage.store(SCOPES[1], "X", &EvArray::F(2.)).unwrap();
+        assert_eq!(run(&storage, writes(None, false), "$X").unwrap(), "2\n");
+        storage.store(SCOPES[2], "X", &EvArray::F(3.)).unwrap();
+        assert_eq!(run(&storage, writes(None, false), "$X").unwrap(), "3\n");
+    }
+
+    #[test]
+    fn guild_variables_need_permission() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        assert!(run(&storage, writes(Some(SCOPES[0]), false), "AC=12")
+            .unwrap_err()
+            .starts_with("Changing the variables of the server"));
+        assert_eq!(var(&storage, SCOPES[0], "AC"), None);
+
+        storage.store(SCOPES[0], "AC", &EvArray::F(12.)).unwrap();
+        // nothing is stored when a single variable cannot be
+        assert!(run(&storage, writes(None, false), "MP=5 AC=20").is_err());
+        assert_eq!(var(&storage, SCOPES[0], "AC"), Some(EvArray::F(12.)));
+        assert_eq!(var(&storage, SCOPES[2], "MP"), None);
+        // reading them is fine
+        assert_eq!(run(&storage, writes(None, false), "$AC").unwrap(), "12\n");
+    }
+
+    #[test]
+    fn variable_limits() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let writes = Writes {
+            target: Some(SCOPES[1]),
+            guild: false,
+            limits: VariableLimits {
+                max_count: 2,
+                max_len: 5,
+            },
+        };
+        run(&storage, writes, "A=1 B=2").unwrap();
+        assert_eq!(
+            run(&storage, writes, "C=3").unwrap_err(),
+            "Cannot store more than 2 channel variables"
+        );
+        run(&storage, writes, "A=5").unwrap();
+        assert_eq!(var(&storage, SCOPES[1], "A"), Some(EvArray::F(5.)));
+        assert_eq!(
+            run(&storage, writes, "B=[1,2,3,4]").unwrap_err(),
+            "Cannot store 'B', which is longer than 5 characters"
+        );
     }
 }
@@ -1,4 +1,9 @@
-use std::num::ParseFloatError;
+use crate::error::{Error, Span};
+use crate::evaluator::BUILTINS;
+
+/// Depth past which expressions and blocks cannot be nested, so that parsing and evaluating
+/// them cannot run out of stack.
+const MAX_NESTING: usize = 128;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Control {
@@ -6,6 +11,10 @@ pub enum Control {
     Else,
     While,
     For,
+    Fn,
+    Return,
+    Break,
+    Continue,
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -119,7 +128,7 @@ impl Expr {
                     )
                 }
             }
-            Expr::None => return Err(format!("Invalid token in expression : 'Operator({})'", 0)),
+            Expr::None => return Err(format!("Invalid token in expression : 'Operator({})'", o)),
             Expr::Var(v) => Self::Operation(
                 Box::new(Self::Var(v.clone())),
                 o,
@@ -198,6 +207,10 @@ pub enum Stmt {
     Condition(Expr, Option<Vec<Stmt>>, Option<Vec<Stmt>>),
     While(Expr, Option<Vec<Stmt>>),
     For(Option<String>, Expr, Option<Vec<Stmt>>),
+    Function(Option<String>, Option<Vec<String>>, Option<Vec<Stmt>>),
+    Return(Expr),
+    Break,
+    Continue,
     StringOut(Expr),
     None,
 }
@@ -206,240 +219,216 @@ pub enum Stmt {
 pub struct Array(Vec<Expr>);
 
 impl Array {
-    pub fn into_inner(&self) -> Vec<Expr> {
-        return self.0.clone();
+    pub fn into_inner(self) -> Vec<Expr> {
+        self.0
     }
 }
 
 fn is_operator(c: char) -> bool {
-    return match c {
-        'd' | '+' | '-' | '*' | '/' | '^' | '<' | '>' | '=' | '|' | '&' | '@' | 'x' | 'l' | 'h'
-        | '_' | 's' | '!' => true,
-        _ => false,
-    };
+    "d+-*/^<>=|&@xlh_s!r".contains(c)
 }
 
 fn get_precedence(c: char) -> usize {
-    return match c {
+    match c {
         'x' => 8,
         'l' | 'h' => 6,
         '+' | '-' => 5,
         '*' | '/' => 4,
         '^' => 3,
         '@' => 2,
-        'd' => 1,
+        'd' | 'r' | 'R' => 1,
         '<' | '>' => 9,
         '=' => 10,
         '&' => 11,
         '|' => 12,
         '_' | 's' | '!' => 7,
         _ => unreachable!(),
-    };
+    }
 }
 
 fn is_unary(c: char) -> bool {
-    return match c {
-        '_' | 's' | '!' => true,
-        _ => false,
-    };
+    matches!(c, '_' | 's' | '!')
 }
 
-fn tokenize_string(chars: Vec<char>, i: &mut usize) -> Vec<Token> {
+/// Tokenizes the content of a string literal, starting right after its opening quote, into
+/// the comma separated codes of its characters. Stops at the closing quote.
+fn tokenize_string(chars: &[(usize, char)], i: &mut usize, end: usize) -> Vec<(Token, Span)> {
     let mut out = vec![];
 
     let mut escape = false;
 
-    while i < &mut chars.len() && (chars[*i] != '"' || escape) {
-        let c = chars[*i];
-        if escape {
-            if c == '\\' {
-                out.push(Token::Number('\\' as u32 as f64));
-                out.push(Token::Comma);
-            } else if c == '"' {
-                out.push(Token::Number('"' as u32 as f64));
-                out.push(Token::Comma);
-            } else if c == 'n' {
-                out.push(Token::Number('\n' as u32 as f64));
-                out.push(Token::Comma);
-            } else if c == 't' {
-                out.push(Token::Number('\t' as u32 as f64));
-                out.push(Token::Comma);
-            }
+    while *i < chars.len() && (chars[*i].1 != '"' || escape) {
+        let (at, c) = chars[*i];
+        let span =
+            if escape { chars[*i - 1].0 } else { at }..chars.get(*i + 1).map_or(end, |c| c.0);
+        let code = if escape {
             escape = false;
+            match c {
+                '\\' => Some('\\'),
+                '"' => Some('"'),
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                _ => None,
+            }
         } else if c == '\\' {
             escape = true;
+            None
         } else {
-            out.push(Token::Number(c as u32 as f64));
-            out.push(Token::Comma);
+            Some(c)
+        };
+        if let Some(code) = code {
+            out.push((Token::Number(code as u32 as f64), span.clone()));
+            out.push((Token::Comma, span));
         }
         *i += 1;
     }
-    if out.len() > 1 {
-        out[0..=out.len() - 2].to_vec()
-    } else {
-        out
-    }
+    out.pop();
+    out
+}
+
+/// Tokenizes `s` without keeping track of where the tokens come from.
+pub fn tokenize(s: String) -> Result<Vec<Token>, Error> {
+    Ok(lex(&s)?.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Whether `s` is a single name, such as `HP` or `max_hp`, and not a keyword or dice.
+pub fn is_name(s: &str) -> bool {
+    matches!(lex(s).as_deref(), Ok([(Token::Variable(v), _)]) if v == s)
 }
 
-pub fn tokenize(s: String) -> Result<Vec<Token>, ParseFloatError> {
-    let chars = s.chars().collect::<Vec<char>>();
+/// Tokenizes `s`, pairing every token with the bytes of `s` it was read from.
+///
+/// Names are made of letters, digits and underscores and start with a letter. Those starting
+/// with an uppercase letter go on while letters are uppercase, the other ones while they are
+/// lowercase, apart from keywords and words only made of dice operators and numbers, like
+/// `d6h3s`, which are read as such. An uppercase word followed by a lowercase name, like
+/// `Max`, is an error rather than two names.
+pub fn lex(s: &str) -> Result<Vec<(Token, Span)>, Error> {
+    const KEYWORDS: [(&str, Control); 8] = [
+        ("if", Control::If),
+        ("else", Control::Else),
+        ("while", Control::While),
+        ("for", Control::For),
+        ("fn", Control::Fn),
+        ("return", Control::Return),
+        ("break", Control::Break),
+        ("continue", Control::Continue),
+    ];
+
+    let chars = s.char_indices().collect::<Vec<(usize, char)>>();
+    // byte offset of the i-th character
+    let at = |i: usize| chars.get(i).map_or(s.len(), |c| c.0);
     let mut out = vec![];
 
-    let mut c_token = Token::None;
     let mut i = 0;
     while i < chars.len() {
-        let c = chars[i];
-        println!("{c}:{i}->{:?}", c_token);
-        match c {
+        let c = chars[i].1;
+        let (token, len) = match c {
             '0'..='9' | '.' => {
-                if let Token::Number(n) = c_token.clone() {
-                    c_token = Token::Number(std::str::FromStr::from_str(
-                        &([n.to_string(), c.to_string()].concat())[..],
-                    )?)
-                } else {
-                    if Token::None != c_token {
-                        out.push(c_token);
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|(_, c)| c.is_ascii_digit() || *c == '.')
+                    .count();
+                let text = &s[at(i)..at(i + len)];
+                match text.parse() {
+                    Ok(n) => (Token::Number(n), len),
+                    Err(_) => {
+                        return Err(Error::Lex(
+                            format!("Invalid number '{}'", text),
+                            at(i)..at(i + len),
+                        ))
                     }
-                    c_token = Token::Number(std::str::FromStr::from_str(&c.to_string()[..])?);
-                }
-            }
-            '(' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::LParen;
-            }
-            ')' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::RParen;
-            }
-            '[' => {
-                if Token::None != c_token {
-                    out.push(c_token);
                 }
-                c_token = Token::LBracket;
             }
-            ']' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::RBracket;
-            }
-            '{' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::LBraces;
-            }
-            '}' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::RBraces;
-            }
-            ',' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::Comma;
-            }
-            ' ' | ';' | '\r' | '\n' | '\t' => {}
-            '$' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::Output;
-            }
-            '~' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                c_token = Token::StringOutput;
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            '[' => (Token::LBracket, 1),
+            ']' => (Token::RBracket, 1),
+            '{' => (Token::LBraces, 1),
+            '}' => (Token::RBraces, 1),
+            ',' => (Token::Comma, 1),
+            '$' => (Token::Output, 1),
+            '~' => (Token::StringOutput, 1),
+            c if c.is_whitespace() || c == ';' => {
+                i += 1;
+                continue;
             }
             '"' => {
-                if Token::None != c_token {
-                    out.push(c_token);
-                }
-                out.push(Token::LBracket);
+                let start = i;
+                out.push((Token::LBracket, at(i)..at(i + 1)));
                 i += 1;
-                out.append(&mut tokenize_string(chars.clone(), &mut i));
-                c_token = Token::RBracket;
-            }
-            c => {
-                if c == 'i' {
-                    if chars.len() - i >= "if".len() {
-                        if &s[i..i + "if".len()] == "if" {
-                            if let Token::None = c_token {
-                            } else {
-                                out.push(c_token);
-                            }
-                            c_token = Token::Control(Control::If);
-                            i += "if".len();
-                            continue;
-                        }
-                    }
-                }
-                if c == 'e' {
-                    if chars.len() - i >= "else".len() {
-                        if &s[i..i + "else".len()] == "else" {
-                            if Token::None != c_token {
-                                out.push(c_token);
-                            }
-                            c_token = Token::Control(Control::Else);
-                            i += "else".len();
-                            continue;
-                        }
-                    }
-                }
-                if c == 'w' {
-                    if chars.len() - i >= "while".len() {
-                        if &s[i..i + "while".len()] == "while" {
-                            if Token::None != c_token {
-                                out.push(c_token);
-                            }
-                            c_token = Token::Control(Control::While);
-                            i += "while".len();
-                            continue;
-                        }
-                    }
+                out.append(&mut tokenize_string(&chars, &mut i, s.len()));
+                if i >= chars.len() {
+                    return Err(Error::Lex(
+                        "Unterminated string".to_owned(),
+                        at(start)..s.len(),
+                    ));
                 }
-                if c == 'f' {
-                    if chars.len() - i >= "for".len() {
-                        if &s[i..i + "for".len()] == "for" {
-                            if Token::None != c_token {
-                                out.push(c_token);
-                            }
-                            c_token = Token::Control(Control::For);
-                            i += "for".len();
-                            continue;
-                        }
+                (Token::RBracket, 1)
+            }
+            c if c.is_alphabetic() => {
+                // words stop where the case changes, so that `4dN` is `4 d N`
+                let upper = c.is_uppercase();
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|(_, c)| {
+                        c.is_ascii_digit()
+                            || *c == '_'
+                            || (c.is_alphabetic() && c.is_uppercase() == upper)
+                    })
+                    .count();
+                let word = &s[at(i)..at(i + len)];
+                if upper {
+                    // `NdS` rolls dice, but `Max` would otherwise be read as `M ax`
+                    let rest = chars[i + len..]
+                        .iter()
+                        .take_while(|(_, c)| {
+                            c.is_ascii_digit() || (c.is_alphabetic() && !c.is_uppercase())
+                        })
+                        .count();
+                    let next = &s[at(i + len)..at(i + len + rest)];
+                    if next.starts_with(char::is_alphabetic)
+                        && !next.chars().all(|c| c.is_ascii_digit() || is_operator(c))
+                    {
+                        return Err(Error::Lex(
+                            format!(
+                                "Invalid name '{}{}' : names are either uppercase or lowercase",
+                                word, next
+                            ),
+                            at(i)..at(i + len + rest),
+                        ));
                     }
                 }
-                if Token::None != c_token {
-                    out.push(c_token.clone());
-                }
-                if c.is_uppercase() {
-                    let mut j = i;
-                    let mut variable = "".to_owned();
-                    while j < chars.len() && (chars[j].is_uppercase()) {
-                        variable.push(chars[j]);
-                        j += 1;
-                    }
-                    c_token = Token::Variable(variable);
-                    i = j; //+ 1;
-                    continue;
-                } else if is_operator(c) {
-                    c_token = Token::Operator(c);
+                // keywords are split from the numbers following them, as in `if1 {$1}`
+                let keyword = KEYWORDS.iter().find(|(k, _)| match word.strip_prefix(k) {
+                    Some(rest) => rest.is_empty() || rest.starts_with(|c: char| c.is_ascii_digit()),
+                    None => false,
+                });
+                if let Some((k, control)) = keyword {
+                    (Token::Control(*control), k.len())
+                } else if upper || !word.chars().all(|c| c.is_ascii_digit() || is_operator(c)) {
+                    (Token::Variable(word.to_owned()), len)
+                } else if word.starts_with("rr") {
+                    // "rr" is stored as 'R' as operators are single characters
+                    (Token::Operator('R'), 2)
+                } else {
+                    // dice notation such as `d6h3s`, whose numbers are read on the next turns
+                    (Token::Operator(c), 1)
                 }
             }
-        }
-        i += 1;
+            c if is_operator(c) => (Token::Operator(c), 1),
+            c => {
+                return Err(Error::Lex(
+                    format!("Unknown character '{}'", c),
+                    at(i)..at(i + 1),
+                ))
+            }
+        };
+        out.push((token, at(i)..at(i + len)));
+        i += len;
     }
-    out.push(c_token);
 
-    return Ok(out);
+    Ok(out)
 }
 
 fn parse_parenthesis(t: &Vec<Token>, i: &mut usize) -> Result<Expr, String> {
@@ -448,7 +437,14 @@ fn parse_parenthesis(t: &Vec<Token>, i: &mut usize) -> Result<Expr, String> {
     while *i < t.len() {
         match t[*i].clone() {
             Token::Number(n) => out = out.add_f(n)?,
-            Token::Variable(v) => out = out.add_var(v)?,
+            Token::Variable(v) => {
+                if t.len() > *i + 1 && matches!(t[*i + 1], Token::LParen) {
+                    *i += 1;
+                    out = out.add_call(v, parse_call(t, i)?)?
+                } else {
+                    out = out.add_var(v)?
+                }
+            }
             Token::Operator(o) => out = out.add_op(o)?,
             Token::LParen => {
                 *i += 1;
@@ -459,12 +455,12 @@ fn parse_parenthesis(t: &Vec<Token>, i: &mut usize) -> Result<Expr, String> {
                 *i += 1;
                 out = out.add_arr(parse_array(t, i)?)?
             }
-            e => return Err(format!("Invalid token in argument {:?} at index {}", e, i)),
+            e => return Err(format!("Invalid token in argument {:?}", e)),
         }
         *i += 1;
     }
 
-    return Ok(Expr::Parenthesised(Box::new(out)));
+    Ok(Expr::Parenthesised(Box::new(out)))
 }
 pub fn parse_call(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Expr>, String> {
     let mut out = vec![];
@@ -481,7 +477,7 @@ pub fn parse_call(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Expr>, String> {
                 current_expr = current_expr.add_op(o)?;
             }
             Token::Variable(v) => {
-                if let Token::LParen = t[*i + 1] {
+                if t.len() > *i + 1 && matches!(t[*i + 1], Token::LParen) {
                     *i += 1;
                     current_expr = current_expr.add_call(v, parse_call(t, i)?)?;
                 } else {
@@ -506,26 +502,47 @@ pub fn parse_call(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Expr>, String> {
             }
             Token::Comma => {
                 if let Expr::None = current_expr {
-                    return Err(format!(
-                        "Invalid token in function call : {:?} at index {}",
-                        t[*i], i
-                    ));
+                    return Err(format!("Invalid token in function call : {:?}", t[*i]));
                 } else {
                     out.push(current_expr.clone());
                     current_expr = Expr::None;
                 }
             }
+            _ => return Err(format!("Invalid token in function call : {:?}", t[*i])),
+        }
+        *i += 1;
+    }
+
+    Ok(out)
+}
+
+pub fn parse_params(t: &[Token], i: &mut usize) -> Result<Vec<String>, String> {
+    let mut out = vec![];
+
+    let mut expect_param = true;
+
+    while *i < t.len() {
+        match t[*i].clone() {
+            Token::Variable(v) if expect_param => {
+                if out.contains(&v) {
+                    return Err(format!("Duplicate parameter '{}'", v));
+                }
+                out.push(v);
+                expect_param = false;
+            }
+            Token::Comma if !expect_param => expect_param = true,
+            Token::RParen if !expect_param || out.is_empty() => break,
             _ => {
                 return Err(format!(
-                    "Invalid token in function call : {:?} at index {}",
-                    t[*i], i
+                    "Invalid token in function parameters : {:?}",
+                    t[*i]
                 ))
             }
         }
         *i += 1;
     }
 
-    return Ok(out);
+    Ok(out)
 }
 
 pub fn parse_array(t: &Vec<Token>, i: &mut usize) -> Result<Array, String> {
@@ -542,7 +559,12 @@ pub fn parse_array(t: &Vec<Token>, i: &mut usize) -> Result<Array, String> {
                 current_expr = current_expr.add_op(o)?;
             }
             Token::Variable(v) => {
-                current_expr = current_expr.add_var(v)?;
+                if t.len() > *i + 1 && matches!(t[*i + 1], Token::LParen) {
+                    *i += 1;
+                    current_expr = current_expr.add_call(v, parse_call(t, i)?)?;
+                } else {
+                    current_expr = current_expr.add_var(v)?;
+                }
             }
             Token::LParen => {
                 *i += 1;
@@ -562,34 +584,71 @@ pub fn parse_array(t: &Vec<Token>, i: &mut usize) -> Result<Array, String> {
             }
             Token::Comma => {
                 if let Expr::None = current_expr {
-                    return Err(format!(
-                        "Invalid token in array : {:?} at index {}",
-                        t[*i], i
-                    ));
+                    return Err(format!("Invalid token in array : {:?}", t[*i]));
                 } else {
                     out.0.push(current_expr.clone());
                     current_expr = Expr::None;
                 }
             }
-            _ => {
-                return Err(format!(
-                    "Invalid token in array : {:?} at index {}",
-                    t[*i], i
-                ))
-            }
+            _ => return Err(format!("Invalid token in array : {:?}", t[*i])),
         }
         *i += 1;
     }
 
-    return Ok(out);
+    Ok(out)
+}
+
+/// Parses a whole program, returning the span of each of its top-level statements along
+/// with them. `len` is the length of the source, where errors at its end point.
+pub fn parse_program(t: &[(Token, Span)], len: usize) -> Result<(Vec<Stmt>, Vec<Span>), Error> {
+    let tokens = t.iter().map(|(t, _)| t.clone()).collect::<Vec<Token>>();
+    let span = |i: usize| t.get(i).map_or(len..len, |(_, s)| s.clone());
+
+    // the parser recurses into brackets, which are checked before it does
+    let mut nested = 0usize;
+    for (k, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LParen | Token::LBracket | Token::LBraces => nested += 1,
+            Token::RParen | Token::RBracket | Token::RBraces => nested = nested.saturating_sub(1),
+            _ => {}
+        }
+        if nested > MAX_NESTING {
+            return Err(Error::Parse(too_deep(), span(k)));
+        }
+    }
+
+    let mut i = 0;
+    // errors are always raised while looking at the offending token
+    let (stmts, starts) = parse_block(&tokens, &mut i).map_err(|e| Error::Parse(e, span(i)))?;
+    if i < tokens.len() {
+        return Err(Error::Parse("Invalid Token '}'".to_owned(), span(i)));
+    }
+
+    let spans = starts
+        .iter()
+        .enumerate()
+        .map(|(k, start)| {
+            let end = starts.get(k + 1).copied().unwrap_or(tokens.len()) - 1;
+            span(*start).start..span(end).end
+        })
+        .collect();
+    Ok((stmts, spans))
 }
 
 pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
+    Ok(parse_block(t, i)?.0)
+}
+
+/// Parses statements up to the end of the current block, also returning the index of the
+/// token each of them starts at.
+fn parse_block(t: &Vec<Token>, i: &mut usize) -> Result<(Vec<Stmt>, Vec<usize>), String> {
     let mut out = vec![];
+    let mut starts = vec![*i];
 
     let mut current_stmt = Stmt::None;
 
     while *i < t.len() {
+        let (len, at) = (out.len(), *i);
         match t[*i].clone() {
             Token::Number(n) => {
                 if let Stmt::Bind(u, expr) = current_stmt.clone() {
@@ -604,15 +663,17 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     current_stmt = Stmt::For(Some(v), expr.add_f(n)?, None)
                 } else if let Stmt::StringOut(expr) = current_stmt.clone() {
                     current_stmt = Stmt::StringOut(expr.add_f(n)?)
+                } else if let Stmt::Return(expr) = current_stmt.clone() {
+                    current_stmt = Stmt::Return(expr.add_f(n)?)
                 } else {
-                    return Err(format!("Invalid Token 'Number({})' at index {}", n, i));
+                    return Err(format!("Invalid Token 'Number({})'", n));
                 }
             }
             Token::Operator(o) => {
                 if let Stmt::Bind(u, expr) = current_stmt.clone() {
                     if let Expr::None = expr {
                         if o != '=' {
-                            return Err(format!("Expected operator '=' at index {}", i));
+                            return Err("Expected operator '='".to_owned());
                         }
                     } else {
                         current_stmt = Stmt::Bind(u, expr.add_op(o)?)
@@ -627,6 +688,8 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     current_stmt = Stmt::While(expr.add_op(o)?, None)
                 } else if let Stmt::For(Some(v), expr, None) = current_stmt.clone() {
                     current_stmt = Stmt::For(Some(v), expr.add_op(o)?, None)
+                } else if let Stmt::Return(expr) = current_stmt.clone() {
+                    current_stmt = Stmt::Return(expr.add_op(o)?)
                 } else {
                     return Err("Invalid Token".to_owned());
                 }
@@ -636,6 +699,8 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     || matches!(current_stmt, Stmt::Condition(_, Some(_), _))
                     || matches!(current_stmt, Stmt::While(_, Some(_)))
                     || matches!(current_stmt, Stmt::For(_, _, Some(_)))
+                    || matches!(current_stmt, Stmt::Function(_, _, Some(_)))
+                    || matches!(current_stmt, Stmt::Break | Stmt::Continue)
                 {
                     if !matches!(current_stmt, Stmt::None) {
                         out.push(current_stmt);
@@ -680,6 +745,19 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                             }
                         }
                     }
+                } else if let Stmt::Return(expr) = current_stmt.clone() {
+                    if t.len() > *i + 1 && matches!(t[*i + 1], Token::LParen) {
+                        *i += 1;
+                        current_stmt = Stmt::Return(expr.add_call(v, parse_call(t, i)?)?);
+                    } else {
+                        match expr.add_var(v.clone()) {
+                            Ok(e) => current_stmt = Stmt::Return(e),
+                            Err(_) => {
+                                out.push(current_stmt);
+                                current_stmt = Stmt::Bind(v, Expr::None);
+                            }
+                        }
+                    }
                 } else if let Stmt::Condition(expr, None, None) = current_stmt.clone() {
                     if t.len() > *i + 1 && matches!(t[*i + 1], Token::LParen) {
                         *i += 1;
@@ -705,11 +783,19 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     } else {
                         current_stmt = Stmt::For(Some(var.clone()), e.add_var(v)?, None);
                     }
+                } else if let Stmt::Function(None, None, None) = current_stmt.clone() {
+                    if BUILTINS.contains(&&v[..]) {
+                        return Err(format!("Cannot redefine builtin function '{}'", v));
+                    }
+                    current_stmt = Stmt::Function(Some(v), None, None);
                 } else {
                     return Err("Invalid Token".to_owned());
                 }
             }
             Token::Control(control) => {
+                if let Stmt::Function(None, None, None) = current_stmt {
+                    return Err("Keywords cannot be used as function names".to_owned());
+                }
                 if let Control::Else = control {
                     if let Stmt::Condition(_, _, _) = current_stmt.clone() {
                         // we should store the fact that the else was there
@@ -737,6 +823,31 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     }
 
                     current_stmt = Stmt::For(None, Expr::None, None);
+                } else if let Control::Fn = control {
+                    if let Stmt::None = current_stmt {
+                    } else {
+                        out.push(current_stmt.clone())
+                    }
+
+                    current_stmt = Stmt::Function(None, None, None);
+                } else if let Control::Return = control {
+                    if let Stmt::None = current_stmt {
+                    } else {
+                        out.push(current_stmt.clone())
+                    }
+
+                    current_stmt = Stmt::Return(Expr::None);
+                } else if let Control::Break | Control::Continue = control {
+                    if let Stmt::None = current_stmt {
+                    } else {
+                        out.push(current_stmt.clone())
+                    }
+
+                    current_stmt = if let Control::Break = control {
+                        Stmt::Break
+                    } else {
+                        Stmt::Continue
+                    };
                 }
             }
             Token::StringOutput => {
@@ -769,11 +880,15 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                 } else if let Stmt::For(Some(v), expr, None) = current_stmt.clone() {
                     current_stmt =
                         Stmt::For(Some(v), expr.add_expr(parse_parenthesis(t, i)?)?, None)
+                } else if let Stmt::Return(expr) = current_stmt.clone() {
+                    current_stmt = Stmt::Return(expr.add_expr(parse_parenthesis(t, i)?)?)
+                } else if let Stmt::Function(Some(n), None, None) = current_stmt.clone() {
+                    current_stmt = Stmt::Function(Some(n), Some(parse_params(t, i)?), None)
                 } else {
-                    return Err(format!("Invalid Token '(' at index {}", i));
+                    return Err("Invalid Token '('".to_owned());
                 }
             }
-            Token::RParen => return Err(format!("Invalid Token ')' at index {}", i)),
+            Token::RParen => return Err("Invalid Token ')'".to_owned()),
             Token::LBracket => {
                 *i += 1;
                 if let Stmt::Bind(u, expr) = current_stmt.clone() {
@@ -788,11 +903,13 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     current_stmt = Stmt::While(expr.add_arr(parse_array(t, i)?)?, None)
                 } else if let Stmt::For(Some(v), expr, None) = current_stmt.clone() {
                     current_stmt = Stmt::For(Some(v), expr.add_arr(parse_array(t, i)?)?, None)
+                } else if let Stmt::Return(expr) = current_stmt.clone() {
+                    current_stmt = Stmt::Return(expr.add_arr(parse_array(t, i)?)?)
                 } else {
-                    return Err(format!("Invalid Token '[' at index {}", i));
+                    return Err("Invalid Token '['".to_owned());
                 }
             }
-            Token::RBracket => return Err(format!("Invalid Token ']' at index {}", i)),
+            Token::RBracket => return Err("Invalid Token ']'".to_owned()),
             Token::LBraces => {
                 *i += 1;
                 match current_stmt.clone() {
@@ -805,14 +922,25 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
                     }
                     Stmt::While(e, None) => current_stmt = Stmt::While(e, Some(parse(t, i)?)),
                     Stmt::For(v, e, None) => current_stmt = Stmt::For(v, e, Some(parse(t, i)?)),
-                    _ => return Err(format!("Invalid Token '{{' at index {}", i)),
+                    Stmt::Function(Some(n), Some(p), None) => {
+                        current_stmt = Stmt::Function(Some(n), Some(p), Some(parse(t, i)?))
+                    }
+                    _ => return Err("Invalid Token '{'".to_owned()),
                 }
             }
             Token::RBraces => {
                 break;
             }
-            Token::Comma => return Err(format!("Invalid Token ',' at index {}", i)),
-            Token::None => return Err(format!("Invalid Token 'None' at index {}", i)),
+            Token::Comma => return Err("Invalid Token ','".to_owned()),
+            Token::None => return Err("Invalid Token 'None'".to_owned()),
+        }
+        // pushing a statement means another one started at this token
+        if out.len() > len {
+            if let Err(e) = check_stmt(&out[len], t, starts[len]) {
+                *i = starts[len];
+                return Err(e);
+            }
+            starts.push(at);
         }
 
         *i += 1;
@@ -822,5 +950,255 @@ pub fn parse(t: &Vec<Token>, i: &mut usize) -> Result<Vec<Stmt>, String> {
     } else {
         out.push(current_stmt.clone());
     }
-    return Ok(out);
+    starts.truncate(out.len());
+    if let (Some(stmt), Some(start)) = (out.last(), starts.last()) {
+        if let Err(e) = check_stmt(stmt, t, *start) {
+            *i = *start;
+            return Err(e);
+        }
+    }
+    Ok((out, starts))
+}
+
+/// Checks that the expression of `stmt`, which starts at the token `start`, is whole, the
+/// statements of its blocks being checked as they are parsed.
+fn check_stmt(stmt: &Stmt, t: &[Token], start: usize) -> Result<(), String> {
+    let e = match stmt {
+        Stmt::Bind(name, Expr::None) if t.get(start + 1) != Some(&Token::Operator('=')) => {
+            return Err(format!("Expected operator '=' after '{}'", name))
+        }
+        // `return` alone returns an empty array
+        Stmt::Return(Expr::None) => return Ok(()),
+        Stmt::Bind(_, e)
+        | Stmt::Out(e)
+        | Stmt::StringOut(e)
+        | Stmt::Return(e)
+        | Stmt::Condition(e, _, _)
+        | Stmt::While(e, _)
+        | Stmt::For(_, e, _) => e,
+        _ => return Ok(()),
+    };
+    if !is_whole(e) {
+        return Err("Expected an expression".to_owned());
+    }
+    // long chains of operators nest as deep as brackets
+    if depth(e) > MAX_NESTING {
+        return Err(too_deep());
+    }
+    Ok(())
+}
+
+fn too_deep() -> String {
+    format!(
+        "Expressions and blocks cannot be nested more than {} deep",
+        MAX_NESTING
+    )
+}
+
+/// Number of operations, brackets and calls nested in `e`, itself included.
+fn depth(e: &Expr) -> usize {
+    match e {
+        Expr::Operation(a, _, b) => 1 + depth(a).max(depth(b)),
+        Expr::Parenthesised(e) => 1 + depth(e),
+        Expr::Array(a) => 1 + a.0.iter().map(depth).max().unwrap_or(0),
+        Expr::Call(_, args) => 1 + args.iter().map(depth).max().unwrap_or(0),
+        Expr::Val(_) | Expr::Var(_) | Expr::None => 0,
+    }
+}
+
+/// Whether `e` misses no operand, unlike `1+` or `()`.
+fn is_whole(e: &Expr) -> bool {
+    match e {
+        Expr::None => false,
+        Expr::Operation(a, _, b) => is_whole(a) && is_whole(b),
+        Expr::Parenthesised(e) => is_whole(e),
+        Expr::Array(a) => a.0.iter().all(is_whole),
+        Expr::Call(_, args) => args.iter().all(is_whole),
+        Expr::Val(_) | Expr::Var(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Token> {
+        tokenize(s.to_owned()).unwrap()
+    }
+
+    fn var(name: &str) -> Token {
+        Token::Variable(name.to_owned())
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            tokens("HP max_hp roll2"),
+            [var("HP"), var("max_hp"), var("roll2")]
+        );
+    }
+
+    #[test]
+    fn names_stop_where_the_case_changes() {
+        assert_eq!(tokens("NdS"), [var("N"), Token::Operator('d'), var("S")]);
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            tokens("if else while for fn return break continue"),
+            [
+                Token::Control(Control::If),
+                Token::Control(Control::Else),
+                Token::Control(Control::While),
+                Token::Control(Control::For),
+                Token::Control(Control::Fn),
+                Token::Control(Control::Return),
+                Token::Control(Control::Break),
+                Token::Control(Control::Continue),
+            ]
+        );
+        assert_eq!(tokens("format"), [var("format")]);
+        assert_eq!(
+            tokens("if1 {$1}"),
+            [
+                Token::Control(Control::If),
+                Token::Number(1.),
+                Token::LBraces,
+                Token::Output,
+                Token::Number(1.),
+                Token::RBraces,
+            ]
+        );
+        assert_eq!(
+            tokens("return2d6"),
+            [
+                Token::Control(Control::Return),
+                Token::Number(2.),
+                Token::Operator('d'),
+                Token::Number(6.),
+            ]
+        );
+        assert_eq!(tokens("iffy if_1"), [var("iffy"), var("if_1")]);
+    }
+
+    #[test]
+    fn dice_notation() {
+        assert_eq!(
+            tokens("4d6h3s"),
+            [
+                Token::Number(4.),
+                Token::Operator('d'),
+                Token::Number(6.),
+                Token::Operator('h'),
+                Token::Number(3.),
+                Token::Operator('s'),
+            ]
+        );
+        assert_eq!(
+            tokens("4d6rr1"),
+            [
+                Token::Number(4.),
+                Token::Operator('d'),
+                Token::Number(6.),
+                Token::Operator('R'),
+                Token::Number(1.),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let spans: Vec<Span> = lex("$ 10d6").unwrap().into_iter().map(|(_, s)| s).collect();
+        assert_eq!(spans, [0..1, 2..4, 4..5, 5..6]);
+    }
+
+    #[test]
+    fn mixed_case_names() {
+        assert_eq!(
+            lex("Max=3"),
+            Err(Error::Lex(
+                "Invalid name 'Max' : names are either uppercase or lowercase".to_owned(),
+                0..3
+            ))
+        );
+        assert_eq!(
+            tokens("Nd6h3s"),
+            [
+                var("N"),
+                Token::Operator('d'),
+                Token::Number(6.),
+                Token::Operator('h'),
+                Token::Number(3.),
+                Token::Operator('s'),
+            ]
+        );
+        assert!(is_name("max_hp") && is_name("HP2"));
+        assert!(!is_name("Max") && !is_name("if") && !is_name("d6") && !is_name("HP hp"));
+    }
+
+    #[test]
+    fn unknown_character() {
+        assert_eq!(
+            lex("$1#2"),
+            Err(Error::Lex("Unknown character '#'".to_owned(), 2..3))
+        );
+    }
+
+    #[test]
+    fn invalid_number() {
+        assert_eq!(
+            lex("$1.2.3"),
+            Err(Error::Lex("Invalid number '1.2.3'".to_owned(), 1..6))
+        );
+    }
+
+    #[test]
+    fn reserved_function_names() {
+        let parse = |s: &str| parse_program(&lex(s).unwrap(), s.len());
+        assert_eq!(
+            parse("fn if(X) {return X}").unwrap_err(),
+            Error::Parse("Keywords cannot be used as function names".to_owned(), 3..5)
+        );
+        assert_eq!(
+            parse("fn EXPLODE(X) {return X}").unwrap_err(),
+            Error::Parse(
+                "Cannot redefine builtin function 'EXPLODE'".to_owned(),
+                3..10
+            )
+        );
+        assert!(parse("fn F(X) {return X}").is_ok());
+    }
+
+    #[test]
+    fn missing_operands() {
+        let parse = |s: &str| parse_program(&lex(s).unwrap(), s.len()).map(|_| ());
+        assert_eq!(
+            parse("$1e400d6").unwrap_err(),
+            Error::Parse("Expected operator '=' after 'e400d6'".to_owned(), 2..8)
+        );
+        let missing = |span| Error::Parse("Expected an expression".to_owned(), span);
+        assert_eq!(parse("$1+").unwrap_err(), missing(0..1));
+        assert_eq!(parse("A=1 B=").unwrap_err(), missing(4..5));
+        assert_eq!(parse("$[1,2+]").unwrap_err(), missing(0..1));
+        assert_eq!(parse("if 1 {$MAX(1+)}").unwrap_err(), missing(6..7));
+        assert_eq!(parse("while {}").unwrap_err(), missing(0..5));
+        assert!(parse("fn F() {return} $F()").is_ok());
+    }
+
+    #[test]
+    fn nesting() {
+        let parse = |s: &str| parse_program(&lex(s).unwrap(), s.len()).map(|_| ());
+        let too_deep = |span| Error::Parse(too_deep(), span);
+        let brackets = |n| format!("${}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(&brackets(MAX_NESTING)).is_ok());
+        assert_eq!(
+            parse(&brackets(MAX_NESTING + 1)).unwrap_err(),
+            too_deep(129..130)
+        );
+        assert_eq!(parse(&"{".repeat(200)).unwrap_err(), too_deep(128..129));
+        let chain = |n| format!("$1{}", "+1".repeat(n));
+        assert!(parse(&chain(MAX_NESTING)).is_ok());
+        assert_eq!(parse(&chain(MAX_NESTING + 1)).unwrap_err(), too_deep(0..1));
+    }
 }
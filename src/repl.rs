@@ -0,0 +1,158 @@
+use crate::cli::{report, EXIT_FAILURE};
+use crate::stats_args;
+use dicer_rs::evaluator::Limits;
+use dicer_rs::parser::{lex, parse_program, Token};
+use dicer_rs::Interpreter;
+use dicer_rs::{probabilities, statistics};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+
+const HELP: &str = "Programs typed are run as they are entered, a line opening a block with { being continued until
+it is closed. Variables and functions remain from one program to the next.
+:vars               lists the variables
+:ast PROGRAM        shows how a program is parsed
+:tokens PROGRAM     shows the tokens of a program
+:seed [N]           shows the seed of the dice, or rolls the following ones from seed N
+:prob EXPR          shows the probability of every result of an expression
+:stats [N] PROGRAM  runs a program many times, 10000 by default, and shows statistics on its output
+:reset              forgets the variables and functions
+:help               shows this help
+:quit               leaves, as does Ctrl-D";
+
+/// File keeping the lines typed from one session to the next.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".dicer_history"))
+}
+
+/// Number of blocks `program` leaves open, which is 0 if it cannot be lexed as the error
+/// is better shown by running it.
+fn open_blocks(program: &str) -> usize {
+    let tokens = match lex(program) {
+        Ok(t) => t,
+        Err(_) => return 0,
+    };
+    let mut depth = 0usize;
+    for (t, _) in tokens {
+        match t {
+            Token::LBraces => depth += 1,
+            Token::RBraces => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// Runs the programs typed line by line, with line editing and a history.
+pub fn run() -> i32 {
+    let mut editor = match DefaultEditor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("unable to read the standard input : {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history yet on the first run
+        let _ = editor.load_history(path);
+    }
+
+    let mut interpreter = Interpreter::default();
+    let mut buffer = String::new();
+    let code = loop {
+        let prompt = if buffer.is_empty() { "> " } else { ". " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C drops the program being typed
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break 0,
+            Err(e) => {
+                eprintln!("unable to read the standard input : {}", e);
+                break EXIT_FAILURE;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if open_blocks(&buffer) > 0 {
+            continue;
+        }
+        let input = std::mem::take(&mut buffer);
+        match input.trim().strip_prefix(':') {
+            Some(command) => {
+                if !meta(&mut interpreter, command) {
+                    break 0;
+                }
+            }
+            None => match interpreter.eval(&input) {
+                Ok(out) => print!("{}", out.text),
+                Err(e) => eprintln!("{}", e.render(&input)),
+            },
+        }
+    };
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("unable to save the history to {} : {}", path.display(), e);
+        }
+    }
+    code
+}
+
+/// Runs a meta-command, returning false to leave.
+fn meta(interpreter: &mut Interpreter, command: &str) -> bool {
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let args = args.trim();
+    match name {
+        "vars" => {
+            let vars = interpreter.vars();
+            let mut names = vars.keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                println!("{} = {}", name, vars[name]);
+            }
+        }
+        "tokens" => match lex(args) {
+            Ok(tokens) => {
+                for (t, span) in tokens {
+                    println!("{:?} {:?}", span, t);
+                }
+            }
+            Err(e) => eprintln!("{}", e.render(args)),
+        },
+        "ast" => match lex(args).and_then(|t| parse_program(&t, args.len())) {
+            Ok((stmts, _)) => {
+                for stmt in stmts {
+                    println!("{:#?}", stmt);
+                }
+            }
+            Err(e) => eprintln!("{}", e.render(args)),
+        },
+        "seed" if args.is_empty() => println!("{}", interpreter.seed()),
+        "seed" => match args.parse() {
+            Ok(seed) => interpreter.reseed(seed),
+            Err(_) => eprintln!("Invalid seed '{}'", args),
+        },
+        "prob" => {
+            report(args, probabilities(args, Limits::default()));
+        }
+        "stats" => {
+            let (runs, program) = stats_args(args);
+            report(program, statistics(program, runs, Limits::default()));
+        }
+        "reset" => *interpreter = Interpreter::default(),
+        "help" => println!("{}", HELP),
+        "quit" | "q" => return false,
+        _ => eprintln!("Unknown command ':{}', :help lists them", name),
+    }
+    true
+}
@@ -0,0 +1,231 @@
+use serde::Deserialize;
+use serenity::model::channel::AttachmentType;
+
+/// Longest message Discord accepts, in characters.
+pub const MESSAGE_LIMIT: usize = 2000;
+
+const TRUNCATED: &str = "... output truncated";
+
+/// How much of an output the bot is willing to send.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputLimits {
+    /// Number of messages an output may be split into.
+    pub max_messages: usize,
+    /// Number of characters past which an output is truncated.
+    pub max_len: usize,
+    /// Whether outputs needing more than `max_messages` messages are sent as a file
+    /// instead of being truncated.
+    pub attach: bool,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        OutputLimits {
+            max_messages: 3,
+            max_len: 100_000,
+            attach: true,
+        }
+    }
+}
+
+/// How an output is sent.
+pub enum Reply {
+    Messages(Vec<String>),
+    /// A short message along with a file holding the whole output.
+    File(String, String),
+}
+
+/// Splits `text` into messages short enough for Discord, truncating it or falling back to
+/// a file according to `limits`.
+pub fn prepare(text: &str, limits: &OutputLimits) -> Reply {
+    let text = if text.chars().count() > limits.max_len {
+        let end = text
+            .char_indices()
+            .nth(limits.max_len)
+            .map_or(text.len(), |c| c.0);
+        format!("{}\n{}", &text[..end], TRUNCATED)
+    } else {
+        text.to_owned()
+    };
+
+    let mut messages = split(&text, MESSAGE_LIMIT);
+    if messages.len() <= limits.max_messages {
+        return Reply::Messages(messages);
+    }
+    if limits.attach {
+        return Reply::File(
+            "The output is too long, here it is as a file".to_owned(),
+            text,
+        );
+    }
+    messages.truncate(limits.max_messages);
+    if let Some(last) = messages.last_mut() {
+        // room for the notice, the closing of a code block and a line break
+        while last.chars().count() + TRUNCATED.len() + 5 > MESSAGE_LIMIT {
+            last.pop();
+        }
+        if last.matches("```").count() % 2 == 1 {
+            last.push_str("\n```");
+        }
+        last.push('\n');
+        last.push_str(TRUNCATED);
+    }
+    Reply::Messages(messages)
+}
+
+/// A text file holding `content`, to attach to a message.
+pub fn attachment(content: String) -> AttachmentType<'static> {
+    AttachmentType::Bytes {
+        data: content.into_bytes().into(),
+        filename: "output.txt".to_owned(),
+    }
+}
+
+/// Splits `text` into chunks of at most `limit` characters, on line breaks when possible.
+/// A code block cut in two is closed at the end of a chunk and reopened in the next one.
+fn split(text: &str, limit: usize) -> Vec<String> {
+    const FENCE: &str = "```";
+    // a chunk may need to close a code block, its reopening being counted in `len`
+    let room = limit - (FENCE.len() + 1);
+
+    let mut out = vec![];
+    let mut current = String::new();
+    let mut len = 0;
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        while !line.is_empty() {
+            let line_len = line.chars().count();
+            if len + line_len <= room {
+                current.push_str(line);
+                len += line_len;
+                break;
+            }
+            // a line that would not fit in the next chunk either is cut, a shorter one moves
+            // to the next chunk
+            let fresh = if in_code { FENCE.len() + 1 } else { 0 };
+            if line_len > room - fresh {
+                let end = line
+                    .char_indices()
+                    .nth(room - len)
+                    .map_or(line.len(), |c| c.0);
+                current.push_str(&line[..end]);
+                line = &line[end..];
+            }
+            if in_code {
+                current.push_str(if current.ends_with('\n') {
+                    FENCE
+                } else {
+                    "\n```"
+                });
+            }
+            out.push(std::mem::take(&mut current));
+            len = 0;
+            if in_code {
+                current.push_str("```\n");
+                len = FENCE.len() + 1;
+            }
+        }
+        if line.trim_start().starts_with(FENCE) {
+            in_code = !in_code;
+        }
+    }
+    if !current.trim().is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(chunks: &[String]) {
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= MESSAGE_LIMIT, "{}", chunk.len());
+            assert_eq!(chunk.matches("```").count() % 2, 0, "{}", chunk);
+        }
+    }
+
+    #[test]
+    fn short_text_is_kept_whole() {
+        assert_eq!(split("1\n2\n", MESSAGE_LIMIT), ["1\n2\n"]);
+    }
+
+    #[test]
+    fn lines_are_not_cut_when_they_fit() {
+        let line = format!("{}\n", "x".repeat(1500));
+        let chunks = split(&line.repeat(2), MESSAGE_LIMIT);
+        assert_eq!(chunks, [line.clone(), line]);
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let text = "é".repeat(5000);
+        let chunks = split(&text, MESSAGE_LIMIT);
+        check(&chunks);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn code_blocks_are_reopened() {
+        let text = format!("```\n{}```", "123456789\n".repeat(500));
+        let chunks = split(&text, MESSAGE_LIMIT);
+        check(&chunks);
+        assert!(chunks.len() > 1);
+        assert!(chunks[1..].iter().all(|c| c.starts_with("```\n")));
+    }
+
+    #[test]
+    fn long_lines_in_code_blocks_end() {
+        for len in 1980..2010 {
+            let text = format!("```\n{}\n```", "x".repeat(len));
+            let chunks = split(&text, MESSAGE_LIMIT);
+            check(&chunks);
+            let xs: usize = chunks.iter().map(|c| c.matches('x').count()).sum();
+            assert_eq!(xs, len);
+        }
+    }
+
+    #[test]
+    fn outputs_past_the_message_limit_are_truncated() {
+        let limits = OutputLimits {
+            max_messages: 2,
+            max_len: 100_000,
+            attach: false,
+        };
+        let text = format!("```\n{}```", "123456789\n".repeat(1000));
+        match prepare(&text, &limits) {
+            Reply::Messages(messages) => {
+                check(&messages);
+                assert_eq!(messages.len(), 2);
+                assert!(messages[1].ends_with(TRUNCATED));
+            }
+            Reply::File(..) => panic!("expected messages"),
+        }
+    }
+
+    #[test]
+    fn outputs_past_the_message_limit_are_attached() {
+        let text = "x\n".repeat(5000);
+        match prepare(&text, &OutputLimits::default()) {
+            Reply::File(_, content) => assert_eq!(content, text),
+            Reply::Messages(_) => panic!("expected a file"),
+        }
+    }
+
+    #[test]
+    fn outputs_past_max_len_are_cut() {
+        let limits = OutputLimits {
+            max_len: 10,
+            ..OutputLimits::default()
+        };
+        match prepare(&"x".repeat(20), &limits) {
+            Reply::Messages(messages) => {
+                assert_eq!(messages, [format!("{}\n{}", "x".repeat(10), TRUNCATED)])
+            }
+            Reply::File(..) => panic!("expected messages"),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{embed, gm, macros, reply, run_program, stats_command, unblock, Handler, Origin};
+use serenity::client::Context;
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::application::interaction::autocomplete::AutocompleteInteraction;
+use tracing::warn;
+
+/// Registers the slash commands globally, replacing the ones registered before.
+pub async fn register(ctx: &Context) -> serenity::Result<Vec<Command>> {
+    Command::set_global_application_commands(&ctx.http, |commands| {
+        commands
+            .create_application_command(|c| {
+                c.name("roll")
+                    .description("Runs a program")
+                    .create_option(|o| {
+                        o.name("program")
+                            .description("The program to run, e.g. $4d6h3s")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+                    .create_option(|o| {
+                        o.name("secret")
+                            .description("Sends the output to you and the GMs by direct message")
+                            .kind(CommandOptionType::Boolean)
+                    })
+            })
+            .create_application_command(|c| {
+                c.name("stats")
+                    .description("Runs a program many times and shows statistics on its output")
+                    .create_option(|o| {
+                        o.name("program")
+                            .description("The program to run, e.g. $4d6h3s")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+                    .create_option(|o| {
+                        o.name("runs")
+                            .description("How many times to run it, 10000 by default")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(1)
+                            .max_int_value(dicer_rs::stats::MAX_RUNS)
+                    })
+            })
+            .create_application_command(|c| {
+                c.name("macro")
+                    .description("Saves programs and runs them by name")
+                    .create_option(|o| {
+                        o.name("run")
+                            .description("Runs a macro")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|o| name_option(o).required(true))
+                            .create_sub_option(|o| {
+                                o.name("arguments")
+                                    .description("Its arguments, separated by commas")
+                                    .kind(CommandOptionType::String)
+                            })
+                    })
+                    .create_option(|o| {
+                        o.name("save")
+                            .description("Saves a macro")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o.name("name")
+                                    .description("Its name and parameters, e.g. ATK(BONUS)")
+                                    .kind(CommandOptionType::String)
+                                    .required(true)
+                            })
+                            .create_sub_option(|o| {
+                                o.name("program")
+                                    .description("The program to save, e.g. $1d20s+BONUS")
+                                    .kind(CommandOptionType::String)
+                                    .required(true)
+                            })
+                            .create_sub_option(scope_option)
+                    })
+                    .create_option(|o| {
+                        o.name("list")
+                            .description("Lists your macros and the ones of this server")
+                            .kind(CommandOptionType::SubCommand)
+                    })
+                    .create_option(|o| {
+                        o.name("forget")
+                            .description("Deletes a macro")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|o| name_option(o).required(true))
+                            .create_sub_option(scope_option)
+                    })
+            })
+            .create_application_command(|c| c.name("help").description("Explains how to use Dicer"))
+    })
+    .await
+}
+
+fn name_option(
+    o: &mut serenity::builder::CreateApplicationCommandOption,
+) -> &mut serenity::builder::CreateApplicationCommandOption {
+    o.name("name")
+        .description("Name of the macro")
+        .kind(CommandOptionType::String)
+        .set_autocomplete(true)
+}
+
+fn scope_option(
+    o: &mut serenity::builder::CreateApplicationCommandOption,
+) -> &mut serenity::builder::CreateApplicationCommandOption {
+    o.name("for")
+        .description("Whose macro it is, yours by default")
+        .kind(CommandOptionType::String)
+        .add_string_choice("me", "user")
+        .add_string_choice("this server", "guild")
+}
+
+/// Value of the option `name` among `options`, as a string.
+fn option(options: &[CommandDataOption], name: &str) -> Option<String> {
+    let value = options.iter().find(|o| o.name == name)?.value.as_ref()?;
+    match value.as_str() {
+        Some(s) => Some(s.to_owned()),
+        None => Some(value.to_string()),
+    }
+}
+
+pub async fn run(handler: &Handler, ctx: &Context, command: &ApplicationCommandInteraction) {
+    let origin = Origin::new(&command.user, command.channel_id, command.guild_id);
+    let storage = &*handler.storage;
+    let options = &command.data.options;
+    let features = handler.config.features(command.guild_id);
+    // program run and dice it rolled, for the commands running one
+    let mut rolled = None;
+
+    // running a program may outlast the 3 seconds Discord waits for a response, so the
+    // response is only a notice that the bot is thinking, replaced by the output later on
+    if let Err(why) = command.defer(&ctx.http).await {
+        warn!(error = ?why, "unable to answer the command");
+        return;
+    }
+
+    let out = match command.data.name.as_str() {
+        "roll" => {
+            let program = option(options, "program").unwrap_or_default();
+            let secret = option(options, "secret").as_deref() == Some("true");
+            if secret && !features.secret_rolls {
+                "Secret rolls are disabled here".to_owned()
+            } else {
+                match handler.writes(ctx, &origin, None).await {
+                    Ok(writes) => {
+                        let (scopes, owned) = (origin.scopes(), program.clone());
+                        let (out, rolls) = handler
+                            .roll(&origin, &program, move |interpreter, storage| {
+                                run_program(interpreter, storage, &scopes, writes, &owned, &[])
+                            })
+                            .await;
+                        if secret {
+                            gm::send(handler, ctx, &origin, &program, (out, rolls)).await
+                        } else {
+                            rolled = Some((program, rolls));
+                            out
+                        }
+                    }
+                    Err(e) => e,
+                }
+            }
+        }
+        "stats" => {
+            let program = option(options, "program").unwrap_or_default();
+            let args = match option(options, "runs") {
+                Some(runs) => format!("{} {}", runs, program),
+                None => program,
+            };
+            let limits = handler.config.limits();
+            unblock(move || stats_command(&args, limits))
+                .await
+                .unwrap_or_else(|e| e)
+        }
+        "macro" => {
+            let sub = &options[0];
+            let scope = option(&sub.options, "for").unwrap_or_else(|| "user".to_owned());
+            let name = option(&sub.options, "name").unwrap_or_default();
+            let scopes = origin.scopes();
+            match sub.name.as_str() {
+                "run" => {
+                    let args = format!(
+                        "{} {}",
+                        name,
+                        option(&sub.options, "arguments").unwrap_or_default()
+                    );
+                    let program = format!("run {}", args.trim());
+                    let scopes = scopes.clone();
+                    match handler.writes(ctx, &origin, None).await {
+                        Ok(writes) => {
+                            let (out, rolls) = handler
+                                .roll(&origin, &program, move |interpreter, storage| {
+                                    macros::run(interpreter, storage, &scopes, writes, &args)
+                                })
+                                .await;
+                            rolled = Some((program, rolls));
+                            out
+                        }
+                        Err(e) => e,
+                    }
+                }
+                "save" => {
+                    let program = option(&sub.options, "program").unwrap_or_default();
+                    let args = format!("{} {} {}", scope, name, program);
+                    match handler.check_macros(ctx, &origin, &args).await {
+                        Ok(()) => macros::save(storage, &scopes, &args).unwrap_or_else(|e| e),
+                        Err(e) => e,
+                    }
+                }
+                "list" => macros::list(storage, &scopes).unwrap_or_else(|e| e),
+                _ => {
+                    let args = format!("{} {}", scope, name);
+                    match handler.check_macros(ctx, &origin, &args).await {
+                        Ok(()) => macros::forget(storage, &scopes, &args).unwrap_or_else(|e| e),
+                        Err(e) => e,
+                    }
+                }
+            }
+        }
+        _ => handler.config.help(command.guild_id),
+    };
+    if let Some((program, rolls)) = rolled {
+        if features.embeds && !rolls.is_empty() && !out.is_empty() && embed::fits(&out) {
+            if let Err(why) = command
+                .edit_original_interaction_response(&ctx.http, |r| {
+                    r.embed(|e| embed::roll(e, &program, &out, &rolls))
+                })
+                .await
+            {
+                warn!(error = ?why, "unable to send message");
+            }
+            return;
+        }
+    }
+
+    // Discord refuses empty messages
+    let out = if out.is_empty() {
+        "Done".to_owned()
+    } else {
+        out
+    };
+
+    // the answer to the command comes first, follow-ups carry the rest of a long output
+    let sent = match reply::prepare(&out, &handler.config.output) {
+        reply::Reply::Messages(messages) => {
+            let mut messages = messages.into_iter();
+            let first = messages.next().unwrap_or_default();
+            let mut sent = command
+                .edit_original_interaction_response(&ctx.http, |r| r.content(first))
+                .await
+                .map(|_| ());
+            for m in messages {
+                if sent.is_err() {
+                    break;
+                }
+                sent = command
+                    .create_followup_message(&ctx.http, |f| f.content(m))
+                    .await
+                    .map(|_| ());
+            }
+            sent
+        }
+        reply::Reply::File(note, content) => {
+            // files cannot be added to the response once deferred, but can to a follow-up
+            match command
+                .edit_original_interaction_response(&ctx.http, |r| r.content(note))
+                .await
+            {
+                Ok(_) => command
+                    .create_followup_message(&ctx.http, |f| f.add_file(reply::attachment(content)))
+                    .await
+                    .map(|_| ()),
+                Err(why) => Err(why),
+            }
+        }
+    };
+    if let Err(why) = sent {
+        warn!(error = ?why, "unable to send message");
+    }
+}
+
+/// Suggests the names of the macros available to the user that start with what was typed.
+pub async fn autocomplete(handler: &Handler, ctx: &Context, a: &AutocompleteInteraction) {
+    let origin = Origin::new(&a.user, a.channel_id, a.guild_id);
+    let typed = a
+        .data
+        .options
+        .iter()
+        .flat_map(|o| o.options.iter())
+        .find(|o| o.focused)
+        .and_then(|o| o.value.as_ref()?.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let mut names = macros::names(&*handler.storage, &origin.scopes()).unwrap_or_default();
+    names.retain(|n| n.to_lowercase().starts_with(&typed));
+    names.truncate(25);
+
+    if let Err(why) = a
+        .create_autocomplete_response(&ctx.http, |r| {
+            for name in names {
+                r.add_string_choice(&name, &name);
+            }
+            r
+        })
+        .await
+    {
+        warn!(error = ?why, "unable to send autocompletion");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::evaluator::{evaluate, EvArray, Limits, State};
+use crate::parser::Stmt;
+
+/// Largest number of runs a single request may ask for.
+pub const MAX_RUNS: usize = 100_000;
+
+/// Results of running a program many times.
+pub struct Samples {
+    values: Vec<f64>,
+    requested: usize,
+}
+
+/// Runs `t` `runs` times, each time from a blank state, and collects the value output by
+/// its last `$` statement, which must be a number.
+///
+/// All runs share the step budget of `limits` : once it is exhausted, sampling stops and
+/// the runs completed so far are kept.
+pub fn sample(t: &[Stmt], runs: usize, limits: Limits) -> Result<Samples, String> {
+    if runs == 0 || runs > MAX_RUNS {
+        return Err(format!(
+            "The number of runs must be between 1 and {}",
+            MAX_RUNS
+        ));
+    }
+    let mut budget = limits.max_steps;
+    let mut values = vec![];
+
+    for _ in 0..runs {
+        let mut mem = State::new(Limits {
+            max_steps: budget,
+            ..limits
+        });
+        if let Err(e) = evaluate(t, &mut mem) {
+            if mem.steps() > budget && !values.is_empty() {
+                break;
+            }
+            return Err(e);
+        }
+        budget -= mem.steps();
+        match mem.take_last() {
+            Some(EvArray::F(f)) => values.push(f),
+            // a single die such as `1d20` rolls into a one-element array
+            Some(EvArray::A(a)) if matches!(a[..], [EvArray::F(_)]) => {
+                if let EvArray::F(f) = a[0] {
+                    values.push(f)
+                }
+            }
+            Some(EvArray::A(_)) => {
+                return Err(
+                    "The program must output a number, not an array. Consider to sum it with 's'."
+                        .to_string(),
+                )
+            }
+            None => return Err("The program must output a number with '$'".to_string()),
+        }
+    }
+
+    Ok(Samples {
+        values,
+        requested: runs,
+    })
+}
+
+/// Renders the mean, standard deviation, extrema, percentiles and a histogram of samples.
+pub fn render(s: &Samples) -> String {
+    const BUCKETS: usize = 20;
+    const BAR: f64 = 30.;
+
+    let mut values = s.values.clone();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    let percentile = |p: f64| values[((p / 100. * n).ceil() as usize).clamp(1, values.len()) - 1];
+
+    let mut out = format!("{} runs", values.len());
+    if values.len() < s.requested {
+        out = format!("{} (stopped early : execution limit exceeded)", out);
+    }
+    out = format!(
+        "{}\nmean : {:.2}, standard deviation : {:.2}, min : {}, max : {}\n",
+        out,
+        mean,
+        var.sqrt(),
+        values[0],
+        values[values.len() - 1]
+    );
+    out = format!(
+        "{}percentiles : 5% {}, 25% {}, 50% {}, 75% {}, 95% {}\n",
+        out,
+        percentile(5.),
+        percentile(25.),
+        percentile(50.),
+        percentile(75.),
+        percentile(95.)
+    );
+
+    // one line per value when there are few of them, equal-width buckets otherwise
+    let mut distinct = values.clone();
+    distinct.dedup();
+    let lines: Vec<(String, usize)> = if distinct.len() <= BUCKETS {
+        distinct
+            .iter()
+            .map(|d| (d.to_string(), values.iter().filter(|v| *v == d).count()))
+            .collect()
+    } else {
+        let (min, max) = (values[0], values[values.len() - 1]);
+        let width = (max - min) / BUCKETS as f64;
+        let mut counts = vec![0; BUCKETS];
+        for v in &values {
+            counts[(((v - min) / width) as usize).min(BUCKETS - 1)] += 1;
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let low = min + width * i as f64;
+                (format!("{:.2}-{:.2}", low, low + width), c)
+            })
+            .collect()
+    };
+    let most = lines.iter().map(|(_, c)| *c).max().unwrap_or(1);
+    for (label, count) in lines {
+        out = format!(
+            "{}{} : {:.2}% {}\n",
+            out,
+            label,
+            count as f64 / n * 100.,
+            "#".repeat((count as f64 / most as f64 * BAR).round() as usize)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{lex, parse_program};
+
+    fn samples(program: &str, runs: usize, limits: Limits) -> Result<Samples, String> {
+        let tokens = lex(program).map_err(|e| e.message().to_owned())?;
+        let (parsed, _) =
+            parse_program(&tokens, program.len()).map_err(|e| e.message().to_owned())?;
+        sample(&parsed, runs, limits)
+    }
+
+    #[test]
+    fn sampling() {
+        let s = samples("$2d6s", 1000, Limits::default()).unwrap();
+        assert_eq!(s.values.len(), 1000);
+        assert!(s.values.iter().all(|v| (2. ..=12.).contains(v)));
+
+        // a single die is a one-element array, which counts as a number
+        let s = samples("$1d20", 10, Limits::default()).unwrap();
+        assert_eq!(s.values.len(), 10);
+    }
+
+    #[test]
+    fn rendering() {
+        let out = render(&samples("$3", 100, Limits::default()).unwrap());
+        assert!(out.starts_with("100 runs\n"), "{}", out);
+        assert!(out.contains("mean : 3.00, standard deviation : 0.00, min : 3, max : 3"));
+        assert!(out.contains("percentiles : 5% 3, 25% 3, 50% 3, 75% 3, 95% 3"));
+        assert!(out.ends_with("3 : 100.00% ##############################\n"));
+    }
+
+    #[test]
+    fn shared_budget() {
+        let limits = Limits {
+            max_steps: 100,
+            ..Limits::default()
+        };
+        let s = samples("$1d6s", 1000, limits).unwrap();
+        assert!(!s.values.is_empty() && s.values.len() < 1000);
+        assert!(render(&s).contains("(stopped early : execution limit exceeded)"));
+    }
+
+    #[test]
+    fn errors() {
+        let err =
+            |program: &str, runs: usize| samples(program, runs, Limits::default()).err().unwrap();
+        assert_eq!(
+            err("$1", 0),
+            "The number of runs must be between 1 and 100000"
+        );
+        assert_eq!(
+            err("$1", MAX_RUNS + 1),
+            "The number of runs must be between 1 and 100000"
+        );
+        assert!(err("$2d6", 10).starts_with("The program must output a number, not an array"));
+        assert_eq!(err("A=1", 10), "The program must output a number with '$'");
+    }
+}
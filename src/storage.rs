@@ -0,0 +1,297 @@
+use dicer_rs::evaluator::EvArray;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+mod sqlite;
+
+pub use sqlite::SqliteStorage;
+
+/// Who a stored variable belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User(u64),
+    Channel(u64),
+    Guild(u64),
+}
+
+impl Scope {
+    /// Name of the kind of scope, as used in commands.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Scope::User(_) => "user",
+            Scope::Channel(_) => "channel",
+            Scope::Guild(_) => "guild",
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Scope::User(id) | Scope::Channel(id) | Scope::Guild(id) => *id,
+        }
+    }
+}
+
+/// How much a single scope may store, so that nobody fills the storage of a whole server.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariableLimits {
+    /// Number of variables a scope may hold.
+    pub max_count: usize,
+    /// Number of characters the value of a variable may take once written out.
+    pub max_len: usize,
+}
+
+impl Default for VariableLimits {
+    fn default() -> Self {
+        VariableLimits {
+            max_count: 100,
+            max_len: 1000,
+        }
+    }
+}
+
+/// A program run by the bot, kept to settle disputes about its result : running it again
+/// from its seed rolls the same dice.
+pub struct Roll<'a> {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub user: u64,
+    pub channel: u64,
+    pub guild: Option<u64>,
+    pub program: &'a str,
+    pub seed: u64,
+}
+
+/// Where the bot keeps its state between messages.
+pub trait Storage: Send + Sync {
+    fn load(&self, scope: Scope) -> Result<HashMap<String, EvArray>, String>;
+    fn store(&self, scope: Scope, name: &str, value: &EvArray) -> Result<(), String>;
+    /// Deletes a variable, returning whether it existed.
+    fn delete(&self, scope: Scope, name: &str) -> Result<bool, String>;
+
+    /// Macros of a scope, by name.
+    fn load_macros(&self, scope: Scope) -> Result<HashMap<String, String>, String>;
+    fn store_macro(&self, scope: Scope, name: &str, program: &str) -> Result<(), String>;
+    /// Deletes a macro, returning whether it existed.
+    fn delete_macro(&self, scope: Scope, name: &str) -> Result<bool, String>;
+
+    /// Setting of a guild, such as its GM role.
+    fn setting(&self, guild: u64, key: &str) -> Result<Option<String>, String>;
+    /// Changes a setting of a guild, removing it if `value` is `None`.
+    fn set_setting(&self, guild: u64, key: &str, value: Option<&str>) -> Result<(), String>;
+
+    /// Adds a roll to the history, which is not kept by default, returning its id if it is.
+    fn record_roll(&self, _roll: &Roll) -> Result<Option<i64>, String> {
+        Ok(None)
+    }
+}
+
+/// Keeps the variables of each scope in a text file of a directory, one `NAME VALUE` line
+/// per variable, and its macros and settings alike in other files.
+pub struct FileStorage {
+    dir: PathBuf,
+    // serializes the read-modify-write cycles of `store` and `delete`
+    lock: Mutex<()>,
+}
+
+impl FileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("unable to create {} : {}", dir.display(), e))?;
+        Ok(FileStorage {
+            dir,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, prefix: &str, scope: Scope) -> PathBuf {
+        self.dir
+            .join(format!("{}{}-{}.txt", prefix, scope.kind(), scope.id()))
+    }
+
+    /// Reads the `NAME VALUE` lines of a file, backslashes and line breaks being escaped in
+    /// values.
+    fn read(&self, prefix: &str, scope: Scope) -> Result<HashMap<String, String>, String> {
+        let path = self.path(prefix, scope);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(format!("unable to read {} : {}", path.display(), e)),
+        };
+        let mut out = HashMap::new();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("invalid line in {} : '{}'", path.display(), line))?;
+            let mut unescaped = String::new();
+            let mut chars = value.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some(c) => unescaped.push(c),
+                        None => {}
+                    },
+                    c => unescaped.push(c),
+                }
+            }
+            out.insert(name.to_owned(), unescaped);
+        }
+        Ok(out)
+    }
+
+    fn write(
+        &self,
+        prefix: &str,
+        scope: Scope,
+        values: &HashMap<String, String>,
+    ) -> Result<(), String> {
+        let path = self.path(prefix, scope);
+        let mut names = values.keys().collect::<Vec<_>>();
+        names.sort();
+        let content = names
+            .into_iter()
+            .map(|n| {
+                let value = values[n].replace('\\', "\\\\").replace('\n', "\\n");
+                format!("{} {}\n", n, value)
+            })
+            .collect::<String>();
+        // written aside first so that a crash cannot leave a truncated file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)
+            .and_then(|_| std::fs::rename(&tmp, &path))
+            .map_err(|e| format!("unable to write {} : {}", path.display(), e))
+    }
+
+    fn insert(&self, prefix: &str, scope: Scope, name: &str, value: String) -> Result<(), String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut values = self.read(prefix, scope)?;
+        values.insert(name.to_owned(), value);
+        self.write(prefix, scope, &values)
+    }
+
+    fn remove(&self, prefix: &str, scope: Scope, name: &str) -> Result<bool, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut values = self.read(prefix, scope)?;
+        if values.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.write(prefix, scope, &values)?;
+        Ok(true)
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, scope: Scope) -> Result<HashMap<String, EvArray>, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut out = HashMap::new();
+        for (name, value) in self.read("", scope)? {
+            out.insert(name, value.parse()?);
+        }
+        Ok(out)
+    }
+
+    fn store(&self, scope: Scope, name: &str, value: &EvArray) -> Result<(), String> {
+        self.insert("", scope, name, value.to_string())
+    }
+
+    fn delete(&self, scope: Scope, name: &str) -> Result<bool, String> {
+        self.remove("", scope, name)
+    }
+
+    fn load_macros(&self, scope: Scope) -> Result<HashMap<String, String>, String> {
+        let _guard = self.lock.lock().unwrap();
+        self.read("macros-", scope)
+    }
+
+    fn store_macro(&self, scope: Scope, name: &str, program: &str) -> Result<(), String> {
+        self.insert("macros-", scope, name, program.to_owned())
+    }
+
+    fn delete_macro(&self, scope: Scope, name: &str) -> Result<bool, String> {
+        self.remove("macros-", scope, name)
+    }
+
+    fn setting(&self, guild: u64, key: &str) -> Result<Option<String>, String> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read("settings-", Scope::Guild(guild))?.remove(key))
+    }
+
+    fn set_setting(&self, guild: u64, key: &str, value: Option<&str>) -> Result<(), String> {
+        match value {
+            Some(v) => self.insert("settings-", Scope::Guild(guild), key, v.to_owned()),
+            None => self
+                .remove("settings-", Scope::Guild(guild), key)
+                .map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCOPES: [Scope; 3] = [Scope::Guild(1), Scope::Channel(1), Scope::User(u64::MAX)];
+
+    /// Stores, reads back and deletes variables, macros and settings, the scopes being kept
+    /// apart.
+    pub(super) fn round_trip(storage: &dyn Storage) {
+        let value = EvArray::A(vec![EvArray::F(1.5), EvArray::A(vec![EvArray::F(-2.)])]);
+        for scope in SCOPES {
+            assert!(storage.load(scope).unwrap().is_empty());
+        }
+        storage.store(SCOPES[1], "HP", &EvArray::F(30.)).unwrap();
+        storage.store(SCOPES[1], "HP", &EvArray::F(26.)).unwrap();
+        storage.store(SCOPES[2], "roll2", &value).unwrap();
+        assert_eq!(
+            storage.load(SCOPES[1]).unwrap(),
+            HashMap::from([("HP".to_owned(), EvArray::F(26.))])
+        );
+        assert_eq!(
+            storage.load(SCOPES[2]).unwrap(),
+            HashMap::from([("roll2".to_owned(), value)])
+        );
+        assert!(storage.load(SCOPES[0]).unwrap().is_empty());
+        assert!(storage.delete(SCOPES[1], "HP").unwrap());
+        assert!(!storage.delete(SCOPES[1], "HP").unwrap());
+        assert!(storage.load(SCOPES[1]).unwrap().is_empty());
+
+        // line breaks and backslashes survive the file backend's escaping
+        let program = "(A) $1d20s+A\n~\"\\\\n\"";
+        storage.store_macro(SCOPES[0], "ATK", program).unwrap();
+        assert_eq!(
+            storage.load_macros(SCOPES[0]).unwrap(),
+            HashMap::from([("ATK".to_owned(), program.to_owned())])
+        );
+        assert!(storage.load_macros(SCOPES[2]).unwrap().is_empty());
+        assert!(storage.delete_macro(SCOPES[0], "ATK").unwrap());
+        assert!(!storage.delete_macro(SCOPES[0], "ATK").unwrap());
+
+        assert_eq!(storage.setting(1, "gm_role").unwrap(), None);
+        storage.set_setting(1, "gm_role", Some("42")).unwrap();
+        assert_eq!(
+            storage.setting(1, "gm_role").unwrap(),
+            Some("42".to_owned())
+        );
+        assert_eq!(storage.setting(2, "gm_role").unwrap(), None);
+        storage.set_setting(1, "gm_role", None).unwrap();
+        assert_eq!(storage.setting(1, "gm_role").unwrap(), None);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("dicer-storage-{}", std::process::id()));
+        round_trip(&FileStorage::new(&dir).unwrap());
+        // the values are read back from the files, not from memory
+        let storage = FileStorage::new(&dir).unwrap();
+        storage.store(SCOPES[0], "AC", &EvArray::F(12.)).unwrap();
+        assert_eq!(
+            FileStorage::new(&dir).unwrap().load(SCOPES[0]).unwrap()["AC"],
+            EvArray::F(12.)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use super::{Roll, Scope, Storage};
+use dicer_rs::evaluator::EvArray;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Schema changes, applied in order. The number of those already applied to a database is
+/// kept in its `user_version`, so a migration must never be edited once released : add a
+/// new one instead.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE variables (
+        scope TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (scope, id, name)
+    );
+    CREATE TABLE macros (
+        scope TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        program TEXT NOT NULL,
+        PRIMARY KEY (scope, id, name)
+    );
+    CREATE TABLE rolls (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        user INTEGER NOT NULL,
+        channel INTEGER NOT NULL,
+        guild INTEGER,
+        program TEXT NOT NULL,
+        seed INTEGER NOT NULL
+    );
+    CREATE TABLE guild_settings (
+        guild INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (guild, key)
+    );
+"];
+
+/// Number of rolls kept in the history by default, the oldest ones being deleted past it.
+const MAX_ROLLS: i64 = 100_000;
+
+/// Keeps the bot's state in a local SQLite database.
+///
+/// Discord ids and seeds are unsigned 64 bits integers while SQLite ones are signed : they
+/// are stored with the same bits, so large seeds read as negative numbers in the database.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    max_rolls: i64,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed, and brings its schema up to date.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut conn = Connection::open(path)
+            .map_err(|e| format!("unable to open {} : {}", path.display(), e))?;
+        migrate(&mut conn).map_err(|e| format!("unable to migrate {} : {}", path.display(), e))?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+            max_rolls: MAX_ROLLS,
+        })
+    }
+}
+
+/// Applies the migrations the database lacks, refusing one written by a newer version whose
+/// schema is unknown.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    let version: i64 = tx
+        .query_row("PRAGMA user_version", [], |r| r.get(0))
+        .map_err(|e| e.to_string())?;
+    let known = MIGRATIONS.len() as i64;
+    if version > known {
+        return Err(format!(
+            "the database has schema version {}, newer than the {} this version knows",
+            version, known
+        ));
+    }
+    if version == known {
+        return Ok(());
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.execute_batch(migration).map_err(|e| e.to_string())?;
+    }
+    tx.pragma_update(None, "user_version", known)
+        .map_err(|e| e.to_string())?;
+    tx.commit().map_err(|e| e.to_string())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self, scope: Scope) -> Result<HashMap<String, EvArray>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached("SELECT name, value FROM variables WHERE scope = ?1 AND id = ?2")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![scope.kind(), scope.id() as i64], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+
+        let mut out = HashMap::new();
+        for row in rows {
+            let (name, value) = row.map_err(|e| e.to_string())?;
+            out.insert(name, value.parse()?);
+        }
+        Ok(out)
+    }
+
+    fn store(&self, scope: Scope, name: &str, value: &EvArray) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO variables (scope, id, name, value) VALUES (?1, ?2, ?3, ?4)",
+                params![scope.kind(), scope.id() as i64, name, value.to_string()],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, scope: Scope, name: &str) -> Result<bool, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM variables WHERE scope = ?1 AND id = ?2 AND name = ?3",
+                params![scope.kind(), scope.id() as i64, name],
+            )
+            .map(|n| n > 0)
+            .map_err(|e| e.to_string())
+    }
+
+    fn load_macros(&self, scope: Scope) -> Result<HashMap<String, String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached("SELECT name, program FROM macros WHERE scope = ?1 AND id = ?2")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![scope.kind(), scope.id() as i64], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    fn store_macro(&self, scope: Scope, name: &str, program: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO macros (scope, id, name, program) VALUES (?1, ?2, ?3, ?4)",
+                params![scope.kind(), scope.id() as i64, name, program],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete_macro(&self, scope: Scope, name: &str) -> Result<bool, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM macros WHERE scope = ?1 AND id = ?2 AND name = ?3",
+                params![scope.kind(), scope.id() as i64, name],
+            )
+            .map(|n| n > 0)
+            .map_err(|e| e.to_string())
+    }
+
+    fn setting(&self, guild: u64, key: &str) -> Result<Option<String>, String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM guild_settings WHERE guild = ?1 AND key = ?2",
+                params![guild as i64, key],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn set_setting(&self, guild: u64, key: &str, value: Option<&str>) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        match value {
+            Some(v) => conn.execute(
+                "INSERT OR REPLACE INTO guild_settings (guild, key, value) VALUES (?1, ?2, ?3)",
+                params![guild as i64, key, v],
+            ),
+            None => conn.execute(
+                "DELETE FROM guild_settings WHERE guild = ?1 AND key = ?2",
+                params![guild as i64, key],
+            ),
+        }
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+
+    fn record_roll(&self, roll: &Roll) -> Result<Option<i64>, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO rolls (time, user, channel, guild, program, seed)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                roll.time as i64,
+                roll.user as i64,
+                roll.channel as i64,
+                roll.guild.map(|g| g as i64),
+                roll.program,
+                roll.seed as i64
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+        // ids only grow, so that the oldest rolls are the ones with the lowest ids
+        let id = conn.last_insert_rowid();
+        conn.execute(
+            "DELETE FROM rolls WHERE id <= ?1",
+            params![id - self.max_rolls],
+        )
+        .map(|_| Some(id))
+        .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roll(program: &str) -> Roll<'_> {
+        Roll {
+            time: 1,
+            user: 2,
+            channel: 3,
+            guild: None,
+            program,
+            seed: u64::MAX,
+        }
+    }
+
+    fn programs(storage: &SqliteStorage) -> Vec<String> {
+        let conn = storage.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT program FROM rolls ORDER BY id")
+            .unwrap();
+        let rows = stmt.query_map([], |r| r.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        crate::storage::tests::round_trip(&SqliteStorage::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn persists() {
+        let path = std::env::temp_dir().join(format!("dicer-{}.db", std::process::id()));
+        SqliteStorage::open(&path)
+            .unwrap()
+            .store(Scope::User(1), "HP", &EvArray::F(30.))
+            .unwrap();
+        let stored = SqliteStorage::open(&path).unwrap().load(Scope::User(1));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stored.unwrap()["HP"], EvArray::F(30.));
+    }
+
+    #[test]
+    fn migrate_from_empty() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+        // migrating again changes nothing
+        migrate(&mut conn).unwrap();
+    }
+
+    #[test]
+    fn migrate_keeps_rolls() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO rolls (time, user, channel, guild, program, seed)
+            VALUES (1, 2, 3, NULL, '$1d6', 4)",
+            [],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let program: String = conn
+            .query_row("SELECT program FROM rolls", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(program, "$1d6");
+    }
+
+    #[test]
+    fn migrate_from_newer() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let newer = MIGRATIONS.len() as i64 + 1;
+        conn.pragma_update(None, "user_version", newer).unwrap();
+        assert_eq!(
+            migrate(&mut conn).unwrap_err(),
+            format!(
+                "the database has schema version {}, newer than the {} this version knows",
+                newer,
+                MIGRATIONS.len()
+            )
+        );
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, newer);
+    }
+
+    #[test]
+    fn rolls_are_pruned() {
+        let mut storage = SqliteStorage::open(":memory:").unwrap();
+        storage.max_rolls = 3;
+        let ids: Vec<_> = ["$1", "$2", "$3", "$4", "$5"]
+            .iter()
+            .map(|program| storage.record_roll(&roll(program)).unwrap())
+            .collect();
+        assert_eq!(ids, [Some(1), Some(2), Some(3), Some(4), Some(5)]);
+        assert_eq!(programs(&storage), ["$3", "$4", "$5"]);
+
+        let seed: i64 = storage
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT seed FROM rolls LIMIT 1", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(seed as u64, u64::MAX);
+    }
+}